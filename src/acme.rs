@@ -0,0 +1,515 @@
+//! Certificates issued and renewed over ACME (RFC 8555), e.g. by Let's Encrypt.
+//!
+//! Every host gets its own certificate, kept in `tls.acme.dir` as `<host>.crt` and `<host>.key`
+//! next to the account key, so a restart serves what it has and only orders what is missing
+//! or due for renewal. Control of a host is proven with HTTP-01, answered on the plain HTTP
+//! listeners, or TLS-ALPN-01 (RFC 8737), answered during the handshake on the TLS listeners.
+//! Hosts are checked at startup and then hourly; a failed order is tried again at the next
+//! check while the previous certificate, if any, keeps serving.
+
+use crate::tls::{self, CertStore};
+use axum::extract::{Path as UrlPath, State};
+use axum::http::header::{CONTENT_TYPE, LOCATION};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::pki_types::PrivateKeyDer;
+use rustls::sign::CertifiedKey;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+/// How often a pending authorization or order is looked at again, and for how many rounds.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: u32 = 60;
+
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+pub enum Challenge {
+    #[serde(rename = "http-01")]
+    Http01,
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+impl Challenge {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Challenge::Http01 => "http-01",
+            Challenge::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+pub struct Settings {
+    /// The directory URL of the ACME server.
+    pub directory: String,
+    pub email: Option<String>,
+    pub hosts: Vec<String>,
+    pub dir: PathBuf,
+    pub ca_file: Option<PathBuf>,
+    pub challenge: Challenge,
+    /// Certificates expiring sooner than this are renewed.
+    pub renew_before: Duration,
+}
+
+pub struct Acme {
+    settings: Settings,
+    client: reqwest::Client,
+    store: Arc<CertStore>,
+    /// Key authorizations of pending HTTP-01 challenges, by token.
+    http_tokens: Mutex<HashMap<String, String>>,
+    /// When each host's current certificate expires.
+    expiry: Mutex<HashMap<String, SystemTime>>,
+}
+
+impl Acme {
+    /// Sets up the client and serves whatever certificates earlier runs left in `dir`.
+    pub fn new(settings: Settings, store: Arc<CertStore>) -> Result<Acme, String> {
+        std::fs::create_dir_all(&settings.dir).map_err(|e: std::io::Error| format!("could not create '{}': {}", settings.dir.display(), e))?;
+
+        let mut builder: reqwest::ClientBuilder = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")));
+        if let Some(path) = &settings.ca_file {
+            let pem: Vec<u8> = std::fs::read(path).map_err(|e: std::io::Error| format!("could not read '{}': {}", path.display(), e))?;
+            let root: reqwest::Certificate = reqwest::Certificate::from_pem(&pem).map_err(|e: reqwest::Error| format!("{}: {}", path.display(), e))?;
+            builder = builder.add_root_certificate(root);
+        }
+        let client: reqwest::Client = builder.build().map_err(|e: reqwest::Error| e.to_string())?;
+
+        let acme: Acme = Acme {
+            settings,
+            client,
+            store,
+            http_tokens: Mutex::new(HashMap::new()),
+            expiry: Mutex::new(HashMap::new()),
+        };
+        for host in &acme.settings.hosts {
+            let (cert, key) = acme.paths(host);
+            let (Ok(cert), Ok(key)) = (std::fs::read(&cert), std::fs::read(&key)) else {
+                continue;
+            };
+            if let Err(e) = acme.install(host, &cert, &key) {
+                tracing::warn!("Ignoring the stored certificate for {}: {}", host, e);
+            }
+        }
+        Ok(acme)
+    }
+
+    pub fn challenge(&self) -> Challenge {
+        self.settings.challenge
+    }
+
+    /// Orders certificates for the hosts that need one, now and after every check interval.
+    pub fn spawn(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                let due: Vec<String> = self.due();
+                if !due.is_empty() {
+                    if let Err(e) = self.renew(&due).await {
+                        tracing::warn!("ACME: could not renew certificates for {}: {}", due.join(", "), e);
+                    }
+                }
+                tokio::time::sleep(CHECK_INTERVAL).await;
+            }
+        });
+    }
+
+    fn paths(&self, host: &str) -> (PathBuf, PathBuf) {
+        (self.settings.dir.join(format!("{}.crt", host)), self.settings.dir.join(format!("{}.key", host)))
+    }
+
+    fn install(&self, host: &str, cert_pem: &[u8], key_pem: &[u8]) -> Result<(), String> {
+        let key: CertifiedKey = tls::certified_key(cert_pem, key_pem)?;
+        let expires: SystemTime = key.cert.first().map(|cert| not_after(cert)).ok_or("empty certificate chain")??;
+        self.store.set(host, Arc::new(key));
+        self.expiry.lock().unwrap().insert(host.to_string(), expires);
+        Ok(())
+    }
+
+    /// Hosts without a certificate or with one inside the renewal window.
+    fn due(&self) -> Vec<String> {
+        let renew_at: SystemTime = SystemTime::now() + self.settings.renew_before;
+        let expiry = self.expiry.lock().unwrap();
+        self.settings
+            .hosts
+            .iter()
+            .filter(|host: &&String| expiry.get(*host).is_none_or(|expires: &SystemTime| *expires <= renew_at))
+            .cloned()
+            .collect()
+    }
+
+    async fn renew(&self, hosts: &[String]) -> Result<(), String> {
+        let mut session: Session = Session::open(self).await?;
+        for host in hosts {
+            match session.issue(host).await {
+                Ok((chain, key)) => match self.save(host, &chain, &key) {
+                    Ok(()) => tracing::info!("ACME: issued a certificate for {}", host),
+                    Err(e) => tracing::warn!("ACME: certificate for {} not saved: {}", host, e),
+                },
+                Err(e) => tracing::warn!("ACME: no certificate for {}: {}", host, e),
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&self, host: &str, chain: &str, key: &str) -> Result<(), String> {
+        self.install(host, chain.as_bytes(), key.as_bytes())?;
+        let (cert_path, key_path) = self.paths(host);
+        write_private(&key_path, key.as_bytes())?;
+        write_private(&cert_path, chain.as_bytes())
+    }
+
+    fn present(&self, host: &str, token: &str, key_authorization: &str) -> Result<(), String> {
+        match self.settings.challenge {
+            Challenge::Http01 => {
+                self.http_tokens.lock().unwrap().insert(token.to_string(), key_authorization.to_string());
+            }
+            Challenge::TlsAlpn01 => {
+                let digest: ring::digest::Digest = ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+                let key: rcgen::KeyPair = rcgen::KeyPair::generate().map_err(|e: rcgen::Error| e.to_string())?;
+                let mut params: rcgen::CertificateParams = rcgen::CertificateParams::new(vec![host.to_string()]).map_err(|e: rcgen::Error| e.to_string())?;
+                params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(digest.as_ref())];
+                let cert: rcgen::Certificate = params.self_signed(&key).map_err(|e: rcgen::Error| e.to_string())?;
+                // Built directly: the key check would reject the critical acmeIdentifier extension.
+                let private: PrivateKeyDer<'static> = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+                let signing_key = rustls::crypto::ring::sign::any_supported_type(&private).map_err(|e: rustls::Error| e.to_string())?;
+                self.store.set_challenge(host, Arc::new(CertifiedKey::new(vec![cert.der().clone()], signing_key)));
+            }
+        }
+        Ok(())
+    }
+
+    fn withdraw(&self, host: &str, token: &str) {
+        match self.settings.challenge {
+            Challenge::Http01 => {
+                self.http_tokens.lock().unwrap().remove(token);
+            }
+            Challenge::TlsAlpn01 => self.store.remove_challenge(host),
+        }
+    }
+}
+
+/// Answers HTTP-01 challenges; meant to be merged into the plain HTTP listeners' router.
+pub fn router(acme: Arc<Acme>) -> Router {
+    Router::new()
+        .route("/.well-known/acme-challenge/:token", get(http_challenge))
+        .with_state(acme)
+}
+
+async fn http_challenge(State(acme): State<Arc<Acme>>, UrlPath(token): UrlPath<String>) -> Response {
+    match acme.http_tokens.lock().unwrap().get(&token) {
+        Some(key_authorization) => ([(CONTENT_TYPE, "application/octet-stream")], key_authorization.clone()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<ChallengeObject>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct ChallengeObject {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+    error: Option<Value>,
+}
+
+/// One conversation with the ACME server under the account key.
+struct Session<'a> {
+    acme: &'a Acme,
+    directory: Directory,
+    rng: SystemRandom,
+    key: EcdsaKeyPair,
+    jwk: Value,
+    /// The JWK thumbprint (RFC 7638) that key authorizations end in.
+    thumbprint: String,
+    /// The account URL, known once the account is registered or found.
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl<'a> Session<'a> {
+    async fn open(acme: &'a Acme) -> Result<Session<'a>, String> {
+        let directory: Directory = acme
+            .client
+            .get(&acme.settings.directory)
+            .send()
+            .await
+            .and_then(|response: reqwest::Response| response.error_for_status())
+            .map_err(|e: reqwest::Error| format!("directory: {}", e))?
+            .json()
+            .await
+            .map_err(|e: reqwest::Error| format!("directory: {}", e))?;
+
+        let rng: SystemRandom = SystemRandom::new();
+        let key: EcdsaKeyPair = account_key(&acme.settings.dir.join("account.key"), &rng)?;
+        // An uncompressed P-256 point: 0x04, then x and y.
+        let point: &[u8] = key.public_key().as_ref();
+        let (x, y) = (URL_SAFE_NO_PAD.encode(&point[1..33]), URL_SAFE_NO_PAD.encode(&point[33..65]));
+        // Members in lexicographic order without whitespace, as the thumbprint requires.
+        let canonical: String = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        let thumbprint: String = URL_SAFE_NO_PAD.encode(ring::digest::digest(&ring::digest::SHA256, canonical.as_bytes()));
+
+        let mut session: Session = Session {
+            acme,
+            directory,
+            rng,
+            key,
+            jwk: json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y }),
+            thumbprint,
+            kid: None,
+            nonce: None,
+        };
+
+        let contact: Vec<String> = acme.settings.email.iter().map(|email: &String| format!("mailto:{}", email)).collect();
+        let new_account: String = session.directory.new_account.clone();
+        let (_, location): (Value, Option<String>) = session
+            .post_json(&new_account, Some(&json!({ "termsOfServiceAgreed": true, "contact": contact })))
+            .await?;
+        session.kid = Some(location.ok_or("the new account has no Location")?);
+        Ok(session)
+    }
+
+    /// Orders a certificate for `host`, returning the PEM chain and private key.
+    async fn issue(&mut self, host: &str) -> Result<(String, String), String> {
+        let new_order: String = self.directory.new_order.clone();
+        let (order, location): (Order, Option<String>) =
+            self.post_json(&new_order, Some(&json!({ "identifiers": [{ "type": "dns", "value": host }] }))).await?;
+        let order_url: String = location.ok_or("the new order has no Location")?;
+
+        for authorization in &order.authorizations {
+            self.authorize(authorization).await?;
+        }
+
+        let key: rcgen::KeyPair = rcgen::KeyPair::generate().map_err(|e: rcgen::Error| e.to_string())?;
+        let mut params: rcgen::CertificateParams = rcgen::CertificateParams::new(vec![host.to_string()]).map_err(|e: rcgen::Error| e.to_string())?;
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let csr: rcgen::CertificateSigningRequest = params.serialize_request(&key).map_err(|e: rcgen::Error| e.to_string())?;
+        let (mut order, _): (Order, Option<String>) = self.post_json(&order.finalize, Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr.der()) }))).await?;
+
+        for _ in 0..POLL_ATTEMPTS {
+            match order.status.as_str() {
+                "valid" => break,
+                "invalid" => return Err(format!("order failed: {}", problem_detail(order.error.as_ref()))),
+                _ => {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                    order = self.post_json(&order_url, None).await?.0;
+                }
+            }
+        }
+        let certificate: String = order
+            .certificate
+            .filter(|_| order.status == "valid")
+            .ok_or("the order was not finished in time")?;
+
+        let chain: String = self
+            .post(&certificate, None)
+            .await?
+            .text()
+            .await
+            .map_err(|e: reqwest::Error| format!("{}: {}", certificate, e))?;
+        Ok((chain, key.serialize_pem()))
+    }
+
+    async fn authorize(&mut self, url: &str) -> Result<(), String> {
+        let (authorization, _): (Authorization, Option<String>) = self.post_json(url, None).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let host: String = authorization.identifier.value;
+        let kind: &str = self.acme.settings.challenge.as_str();
+        let challenge: &ChallengeObject = authorization
+            .challenges
+            .iter()
+            .find(|challenge: &&ChallengeObject| challenge.kind == kind)
+            .ok_or_else(|| format!("{} has no {} challenge", host, kind))?;
+
+        let key_authorization: String = format!("{}.{}", challenge.token, self.thumbprint);
+        self.acme.present(&host, &challenge.token, &key_authorization)?;
+        let result: Result<(), String> = self.validate(url, &challenge.url).await;
+        self.acme.withdraw(&host, &challenge.token);
+        result
+    }
+
+    /// Tells the server the challenge is ready and waits for its verdict.
+    async fn validate(&mut self, authorization_url: &str, challenge_url: &str) -> Result<(), String> {
+        self.post_json::<Value>(challenge_url, Some(&json!({}))).await?;
+        for _ in 0..POLL_ATTEMPTS {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let (authorization, _): (Authorization, Option<String>) = self.post_json(authorization_url, None).await?;
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" => {}
+                _ => {
+                    let error: Option<&Value> = authorization.challenges.iter().find_map(|challenge: &ChallengeObject| challenge.error.as_ref());
+                    return Err(format!("validation failed: {}", problem_detail(error)));
+                }
+            }
+        }
+        Err("validation was not finished in time".to_string())
+    }
+
+    async fn post_json<T: DeserializeOwned>(&mut self, url: &str, payload: Option<&Value>) -> Result<(T, Option<String>), String> {
+        let response: reqwest::Response = self.post(url, payload).await?;
+        let location: Option<String> = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .map(str::to_string);
+        let body: T = response.json().await.map_err(|e: reqwest::Error| format!("{}: {}", url, e))?;
+        Ok((body, location))
+    }
+
+    /// A JWS-signed POST; without a payload it is a POST-as-GET. A rejected nonce is retried
+    /// once with the fresh one the error came with.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<reqwest::Response, String> {
+        let mut retried: bool = false;
+        loop {
+            let nonce: String = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected: Value = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+            let protected: String = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let payload: String = payload.map(|payload: &Value| URL_SAFE_NO_PAD.encode(payload.to_string())).unwrap_or_default();
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|_| "could not sign the request".to_string())?;
+            let body: Value = json!({ "protected": protected, "payload": payload, "signature": URL_SAFE_NO_PAD.encode(signature) });
+
+            let response: reqwest::Response = self
+                .acme
+                .client
+                .post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await
+                .map_err(|e: reqwest::Error| format!("{}: {}", url, e))?;
+            self.nonce = replay_nonce(&response);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+
+            let status: reqwest::StatusCode = response.status();
+            let problem: Value = response.json().await.unwrap_or(Value::Null);
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(format!("{} answered {}: {}", url, status, problem_detail(Some(&problem))));
+        }
+    }
+
+    async fn new_nonce(&self) -> Result<String, String> {
+        let response: reqwest::Response = self
+            .acme
+            .client
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(|e: reqwest::Error| format!("{}: {}", self.directory.new_nonce, e))?;
+        replay_nonce(&response).ok_or_else(|| format!("{} sent no Replay-Nonce", self.directory.new_nonce))
+    }
+}
+
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get("replay-nonce")
+        .and_then(|value: &HeaderValue| value.to_str().ok())
+        .map(str::to_string)
+}
+
+fn problem_detail(problem: Option<&Value>) -> String {
+    match problem {
+        Some(problem) => problem["detail"].as_str().or(problem["type"].as_str()).unwrap_or("no details given").to_string(),
+        None => "no details given".to_string(),
+    }
+}
+
+/// Loads the account key, creating one on first use.
+fn account_key(path: &Path, rng: &SystemRandom) -> Result<EcdsaKeyPair, String> {
+    let pem: String = match std::fs::read_to_string(path) {
+        Ok(pem) => pem,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key: rcgen::KeyPair = rcgen::KeyPair::generate().map_err(|e: rcgen::Error| e.to_string())?;
+            write_private(path, key.serialize_pem().as_bytes())?;
+            tracing::info!("ACME: created account key {}", path.display());
+            key.serialize_pem()
+        }
+        Err(e) => return Err(format!("could not read '{}': {}", path.display(), e)),
+    };
+    let key: rcgen::KeyPair = rcgen::KeyPair::from_pem(&pem).map_err(|e: rcgen::Error| format!("{}: {}", path.display(), e))?;
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.serialize_der(), rng)
+        .map_err(|e: ring::error::KeyRejected| format!("{}: not a P-256 key: {}", path.display(), e))
+}
+
+/// Writes through a temporary file so a reload never sees half a file, readable only by the
+/// owner since it may hold a private key.
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), String> {
+    use std::io::Write;
+
+    let mut temporary: std::ffi::OsString = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut options: std::fs::OpenOptions = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+        .open(&temporary)
+        .and_then(|mut file: std::fs::File| file.write_all(contents))
+        .and_then(|_| std::fs::rename(&temporary, path))
+        .map_err(|e: std::io::Error| format!("could not write '{}': {}", path.display(), e))
+}
+
+fn not_after(cert: &[u8]) -> Result<SystemTime, String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).map_err(|e| format!("bad certificate: {}", e))?;
+    let secs: i64 = cert.validity().not_after.timestamp();
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64))
+}
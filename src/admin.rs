@@ -0,0 +1,20 @@
+//! The admin listener: operational endpoints kept off the client-facing ports. The health
+//! routes are served here too.
+//!
+//! It only listens when `admin.bind` is set, and should not be exposed to the internet.
+
+use crate::metrics::Metrics;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use std::sync::Arc;
+
+pub fn router(metrics: Arc<Metrics>) -> Router {
+    Router::new().route("/metrics", get(metrics_handler)).with_state(metrics)
+}
+
+async fn metrics_handler(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics.render())
+}
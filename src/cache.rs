@@ -0,0 +1,871 @@
+//! Response cache in front of the upstream call.
+//!
+//! Entries are stored as the upstream sent them (before any HTML or origin rewriting) and
+//! follow shared-cache rules from RFC 9111: `Cache-Control`, `Expires`, heuristic freshness
+//! from `Last-Modified`, validators for conditional revalidation and `Vary`. A size-bounded
+//! LRU holds entries in memory; an optional directory, bounded the same way, keeps them
+//! across restarts.
+
+use axum::body::Bytes;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Statuses that may be cached without explicit freshness information.
+const HEURISTIC_STATUSES: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// Upper bound on heuristic freshness derived from `Last-Modified`.
+const MAX_HEURISTIC_FRESHNESS: u64 = 24 * 60 * 60;
+
+#[derive(Clone)]
+pub struct CacheConfig {
+    pub max_memory_bytes: usize,
+    pub max_object_bytes: usize,
+    pub disk_dir: Option<PathBuf>,
+    pub max_disk_bytes: u64,
+    /// Applied when the upstream does not send `stale-while-revalidate` itself.
+    pub stale_while_revalidate: Duration,
+    /// Applied when the upstream does not send `stale-if-error` itself.
+    pub stale_if_error: Duration,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub key: String,
+    pub status: u16,
+    pub headers: Vec<(String, Vec<u8>)>,
+    #[serde(skip)]
+    pub body: Bytes,
+    /// Random, and kept when only the headers are refreshed, so anything derived from the
+    /// body can be keyed by it.
+    #[serde(default)]
+    body_id: u64,
+    /// `Vary` header names the entry was stored under, so lookups can rebuild its key.
+    #[serde(default)]
+    vary: Vec<String>,
+    stored_at: u64,
+    fresh_for: u64,
+    stale_while_revalidate: u64,
+    stale_if_error: u64,
+}
+
+impl CachedResponse {
+    /// An entry with no freshness of its own, for copies kept outside the cache policy.
+    pub fn new(key: String, status: StatusCode, headers: &HeaderMap, body: Bytes) -> CachedResponse {
+        CachedResponse {
+            key,
+            status: status.as_u16(),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.as_str().to_string(), v.as_bytes().to_vec()))
+                .collect(),
+            body,
+            body_id: fastrand::u64(..),
+            vary: Vec::new(),
+            stored_at: now(),
+            fresh_for: 0,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+        }
+    }
+
+    pub fn body_id(&self) -> u64 {
+        self.body_id
+    }
+
+    pub fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK)
+    }
+
+    pub fn header_map(&self) -> HeaderMap {
+        let mut headers: HeaderMap = HeaderMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_bytes(value)) {
+                headers.append(name, value);
+            }
+        }
+        headers
+    }
+
+    pub fn age(&self) -> u64 {
+        now().saturating_sub(self.stored_at)
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.age() < self.fresh_for
+    }
+
+    pub fn within_stale_while_revalidate(&self) -> bool {
+        self.age() < self.fresh_for + self.stale_while_revalidate
+    }
+
+    pub fn within_stale_if_error(&self) -> bool {
+        self.age() < self.fresh_for + self.stale_if_error
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, v)| std::str::from_utf8(v).ok())
+    }
+
+    /// Adds `If-None-Match` / `If-Modified-Since` for a conditional upstream request.
+    pub fn add_validators(&self, headers: &mut HeaderMap) {
+        if let Some(etag) = self.header("etag").and_then(|v: &str| HeaderValue::from_str(v).ok()) {
+            headers.insert("if-none-match", etag);
+        }
+        if let Some(last_modified) = self.header("last-modified").and_then(|v: &str| HeaderValue::from_str(v).ok()) {
+            headers.insert("if-modified-since", last_modified);
+        }
+    }
+
+    /// True when the client's `If-None-Match` / `If-Modified-Since` already matches this
+    /// entry, so a `304` can be answered without a body.
+    pub fn matches_validators(&self, req_headers: &HeaderMap) -> bool {
+        if !(200..300).contains(&self.status) {
+            return false;
+        }
+
+        if let Some(if_none_match) = req_headers.get("if-none-match").and_then(|v: &HeaderValue| v.to_str().ok()) {
+            let Some(etag) = self.header("etag") else {
+                return false;
+            };
+            let etag: &str = etag.trim_start_matches("W/");
+            return if_none_match
+                .split(',')
+                .map(|tag: &str| tag.trim().trim_start_matches("W/"))
+                .any(|tag: &str| tag == "*" || tag == etag);
+        }
+
+        match (header_date(req_headers, "if-modified-since"), self.header("last-modified").and_then(|v: &str| httpdate::parse_http_date(v).ok())) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    fn weight(&self) -> usize {
+        self.key.len() + self.body.len() + self.headers.iter().map(|(n, v)| n.len() + v.len()).sum::<usize>()
+    }
+}
+
+pub struct Cache {
+    config: CacheConfig,
+    memory: Mutex<MemoryTier>,
+    disk: Mutex<DiskTier>,
+    revalidating: Mutex<HashSet<String>>,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Cache {
+        if let Some(dir) = &config.disk_dir {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                tracing::error!("Cache: could not create {}: {}", dir.display(), e);
+            }
+        }
+
+        Cache {
+            memory: Mutex::new(MemoryTier::new(config.max_memory_bytes)),
+            disk: Mutex::new(DiskTier::scan(config.disk_dir.as_deref(), config.max_disk_bytes)),
+            config,
+            revalidating: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Only safe, body-less requests without credentials are served from cache. Range
+    /// requests go straight to the upstream, since a partial response is not the whole page.
+    pub fn is_cacheable_request(method: &Method, headers: &HeaderMap) -> bool {
+        if !matches!(*method, Method::GET | Method::HEAD) || headers.contains_key("authorization") || headers.contains_key("range") {
+            return false;
+        }
+        !directives(headers).contains_key("no-store")
+    }
+
+    /// True when a response's `Cache-Control` keeps it out of shared caches.
+    pub fn is_private_response(headers: &HeaderMap) -> bool {
+        let cc: HashMap<String, Option<String>> = directives(headers);
+        cc.contains_key("no-store") || cc.contains_key("private")
+    }
+
+    pub fn primary_key(method: &Method, host: &str, uri: &Uri) -> String {
+        let query: String = uri.query().map(|q: &str| format!("?{}", q)).unwrap_or_default();
+        format!("{} {}{}{}", method, host.to_ascii_lowercase(), uri.path(), query)
+    }
+
+    pub async fn lookup(&self, primary: &str, req_headers: &HeaderMap) -> Option<Arc<CachedResponse>> {
+        let vary: Vec<String> = match self.vary_names(primary).await {
+            Some(names) => names,
+            None => return None,
+        };
+        let key: String = full_key(primary, &vary, req_headers);
+
+        if let Some(entry) = self.memory.lock().unwrap().get(&key) {
+            return Some(entry);
+        }
+
+        let entry: Arc<CachedResponse> = Arc::new(self.read_disk(&key).await?);
+        self.memory.lock().unwrap().insert(entry.clone());
+        Some(entry)
+    }
+
+    /// Stores a complete upstream response if its headers allow a shared cache to keep it.
+    pub async fn store(&self, primary: &str, req_headers: &HeaderMap, status: StatusCode, resp_headers: &HeaderMap, body: Bytes) {
+        if body.len() > self.config.max_object_bytes {
+            return;
+        }
+        let Some(policy) = self.policy(status, resp_headers) else {
+            return;
+        };
+
+        let vary: Vec<String> = vary_names(resp_headers);
+        let key: String = full_key(primary, &vary, req_headers);
+
+        let mut entry: CachedResponse = CachedResponse::new(key, status, resp_headers, body);
+        entry.vary = vary.clone();
+        entry.fresh_for = policy.fresh_for;
+        entry.stale_while_revalidate = policy.stale_while_revalidate;
+        entry.stale_if_error = policy.stale_if_error;
+
+        self.write_disk(primary, &vary, &entry).await;
+        self.memory.lock().unwrap().insert(Arc::new(entry));
+    }
+
+    /// Applies the headers of a `304 Not Modified` to a stale entry and stores the result.
+    pub async fn refresh(&self, primary: &str, entry: &CachedResponse, not_modified: &HeaderMap) -> Arc<CachedResponse> {
+        let mut headers: HeaderMap = entry.header_map();
+        for name in not_modified.keys() {
+            if name == "content-length" || name == "content-type" {
+                continue;
+            }
+            headers.remove(name);
+            for value in not_modified.get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+
+        let mut refreshed: CachedResponse = entry.clone();
+        refreshed.headers = headers
+            .iter()
+            .map(|(n, v)| (n.as_str().to_string(), v.as_bytes().to_vec()))
+            .collect();
+        refreshed.stored_at = now();
+        refreshed.vary = vary_names(&headers);
+        if let Some(policy) = self.policy(entry.status(), &headers) {
+            refreshed.fresh_for = policy.fresh_for;
+            refreshed.stale_while_revalidate = policy.stale_while_revalidate;
+            refreshed.stale_if_error = policy.stale_if_error;
+        }
+
+        let refreshed: Arc<CachedResponse> = Arc::new(refreshed);
+        self.write_disk(primary, &refreshed.vary, &refreshed).await;
+        self.memory.lock().unwrap().insert(refreshed.clone());
+        refreshed
+    }
+
+    /// Marks a key as being revalidated in the background. Returns false if another
+    /// request already started one.
+    pub fn begin_revalidation(&self, key: &str) -> bool {
+        self.revalidating.lock().unwrap().insert(key.to_string())
+    }
+
+    pub fn end_revalidation(&self, key: &str) {
+        self.revalidating.lock().unwrap().remove(key);
+    }
+
+    /// Passes an upstream body through while keeping a copy, which is stored once the
+    /// stream completes.
+    pub fn tee<S, E>(
+        self: Arc<Self>,
+        upstream: S,
+        primary: String,
+        req_headers: HeaderMap,
+        status: StatusCode,
+        resp_headers: HeaderMap,
+    ) -> impl Stream<Item = Result<Bytes, E>> + Send + Unpin + 'static
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + Unpin + 'static,
+        E: Send + 'static,
+    {
+        let max: usize = self.config.max_object_bytes;
+        tee(upstream, max, move |body: Bytes| {
+            tokio::spawn(async move {
+                self.store(&primary, &req_headers, status, &resp_headers, body).await;
+            });
+        })
+    }
+
+    /// Works out how long a response may be served, or `None` if it must not be stored.
+    fn policy(&self, status: StatusCode, headers: &HeaderMap) -> Option<Policy> {
+        if Cache::is_private_response(headers) || headers.contains_key("set-cookie") {
+            return None;
+        }
+        // Partial content would be served as if it were the whole resource.
+        if status == StatusCode::PARTIAL_CONTENT || headers.contains_key("content-range") {
+            return None;
+        }
+        if vary_names(headers).iter().any(|name: &String| name == "*") {
+            return None;
+        }
+
+        let cc: HashMap<String, Option<String>> = directives(headers);
+        let seconds = |name: &str| -> Option<u64> { cc.get(name).cloned().flatten().and_then(|v: String| v.parse().ok()) };
+        let date: SystemTime = header_date(headers, "date").unwrap_or_else(SystemTime::now);
+
+        let explicit: Option<u64> = seconds("s-maxage").or_else(|| seconds("max-age")).or_else(|| {
+            header_date(headers, "expires").map(|expires: SystemTime| {
+                expires.duration_since(date).map(|d: Duration| d.as_secs()).unwrap_or(0)
+            })
+        });
+
+        let fresh_for: u64 = if cc.contains_key("no-cache") {
+            0
+        } else if let Some(secs) = explicit {
+            secs
+        } else if HEURISTIC_STATUSES.contains(&status.as_u16()) {
+            header_date(headers, "last-modified")
+                .and_then(|modified: SystemTime| date.duration_since(modified).ok())
+                .map(|d: Duration| (d.as_secs() / 10).min(MAX_HEURISTIC_FRESHNESS))
+                .unwrap_or(0)
+        } else {
+            return None;
+        };
+
+        let has_validator: bool = headers.contains_key("etag") || headers.contains_key("last-modified");
+        let must_revalidate: bool = cc.contains_key("must-revalidate") || cc.contains_key("proxy-revalidate");
+
+        let policy = Policy {
+            fresh_for,
+            stale_while_revalidate: if must_revalidate {
+                0
+            } else {
+                seconds("stale-while-revalidate").unwrap_or(self.config.stale_while_revalidate.as_secs())
+            },
+            stale_if_error: if must_revalidate {
+                0
+            } else {
+                seconds("stale-if-error").unwrap_or(self.config.stale_if_error.as_secs())
+            },
+        };
+
+        let useful: bool = policy.fresh_for > 0 || has_validator || policy.stale_if_error > 0;
+        useful.then_some(policy)
+    }
+
+    /// The `Vary` names of the entries in memory, or else the ones recorded on disk.
+    async fn vary_names(&self, primary: &str) -> Option<Vec<String>> {
+        if let Some(names) = self.memory.lock().unwrap().vary(primary) {
+            return Some(names);
+        }
+
+        let dir: &PathBuf = self.config.disk_dir.as_ref()?;
+        let path: PathBuf = dir.join(format!("{:016x}.vary", fnv1a(primary)));
+        let raw: String = tokio::fs::read_to_string(&path).await.ok()?;
+        self.disk.lock().unwrap().touch(&path);
+        Some(raw.lines().filter(|l: &&str| !l.is_empty()).map(str::to_string).collect())
+    }
+
+    async fn read_disk(&self, key: &str) -> Option<CachedResponse> {
+        let dir: &PathBuf = self.config.disk_dir.as_ref()?;
+        let entry: CachedResponse = read_entry(dir, key).await?;
+        self.disk.lock().unwrap().touch(&entry_path(dir, key));
+        Some(entry)
+    }
+
+    async fn write_disk(&self, primary: &str, vary: &[String], entry: &CachedResponse) {
+        let Some(dir) = self.config.disk_dir.as_ref() else {
+            return;
+        };
+
+        let vary_path: PathBuf = dir.join(format!("{:016x}.vary", fnv1a(primary)));
+        let vary_raw: String = vary.join("\n");
+        if let Err(e) = write_atomic(&vary_path, vary_raw.as_bytes()).await {
+            tracing::error!("Cache: failed to write {}: {}", vary_path.display(), e);
+            return;
+        }
+        let Some(size) = write_entry(dir, entry).await else {
+            return;
+        };
+
+        let evicted: Vec<PathBuf> = {
+            let mut disk = self.disk.lock().unwrap();
+            disk.insert(vary_path, vary_raw.len() as u64);
+            disk.insert(entry_path(dir, &entry.key), size)
+        };
+        for path in evicted {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                tracing::debug!("Cache: could not evict {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Passes a body stream through unchanged while buffering a copy. `on_complete` gets the
+/// copy once the stream ends cleanly; bodies over `max` bytes or that fail midway are dropped.
+pub fn tee<S, E, F>(upstream: S, max: usize, on_complete: F) -> impl Stream<Item = Result<Bytes, E>> + Send + Unpin + 'static
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + Unpin + 'static,
+    E: Send + 'static,
+    F: FnOnce(Bytes) + Send + 'static,
+{
+    let mut buffer: Option<Vec<u8>> = Some(Vec::new());
+    let mut on_complete: Option<F> = Some(on_complete);
+    let end = futures_util::stream::once(futures_util::future::ready(None));
+
+    Box::pin(upstream.map(Some).chain(end).filter_map(move |item: Option<Result<Bytes, E>>| {
+        let out: Option<Result<Bytes, E>> = match item {
+            Some(Ok(chunk)) => {
+                if let Some(buf) = buffer.as_mut() {
+                    if buf.len() + chunk.len() > max {
+                        buffer = None;
+                    } else {
+                        buf.extend_from_slice(&chunk);
+                    }
+                }
+                Some(Ok(chunk))
+            }
+            Some(Err(e)) => {
+                buffer = None;
+                Some(Err(e))
+            }
+            None => {
+                if let (Some(buf), Some(on_complete)) = (buffer.take(), on_complete.take()) {
+                    on_complete(Bytes::from(buf));
+                }
+                None
+            }
+        };
+        futures_util::future::ready(out)
+    }))
+}
+
+/// Reads an entry written by [`write_entry`], checking the stored key against hash collisions.
+pub async fn read_entry(dir: &Path, key: &str) -> Option<CachedResponse> {
+    let raw: Vec<u8> = tokio::fs::read(entry_path(dir, key)).await.ok()?;
+    let split: usize = raw.iter().position(|b: &u8| *b == b'\n')?;
+    let mut entry: CachedResponse = serde_json::from_slice(&raw[..split]).ok()?;
+    if entry.key != key {
+        return None;
+    }
+    entry.body = Bytes::copy_from_slice(&raw[split + 1..]);
+    Some(entry)
+}
+
+/// Writes an entry as one line of JSON metadata followed by the raw body, returning the
+/// size of the file.
+pub async fn write_entry(dir: &Path, entry: &CachedResponse) -> Option<u64> {
+    let mut raw: Vec<u8> = serde_json::to_vec(entry).ok()?;
+    raw.push(b'\n');
+    raw.extend_from_slice(&entry.body);
+
+    let entry_path: PathBuf = entry_path(dir, &entry.key);
+    if let Err(e) = write_atomic(&entry_path, &raw).await {
+        tracing::error!("Cache: failed to write {}: {}", entry_path.display(), e);
+        return None;
+    }
+    Some(raw.len() as u64)
+}
+
+fn entry_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{:016x}.entry", fnv1a(key)))
+}
+
+struct Policy {
+    fresh_for: u64,
+    stale_while_revalidate: u64,
+    stale_if_error: u64,
+}
+
+/// Least-recently-used map bounded by the total size of the cached responses.
+pub struct MemoryTier {
+    entries: HashMap<String, (Arc<CachedResponse>, u64)>,
+    order: BTreeMap<u64, String>,
+    /// `Vary` names per primary key and how many entries hold them, dropped with the last one.
+    vary: HashMap<String, (Vec<String>, usize)>,
+    bytes: usize,
+    max_bytes: usize,
+    tick: u64,
+}
+
+impl MemoryTier {
+    pub fn new(max_bytes: usize) -> MemoryTier {
+        MemoryTier {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            vary: HashMap::new(),
+            bytes: 0,
+            max_bytes,
+            tick: 0,
+        }
+    }
+
+    fn vary(&self, primary: &str) -> Option<Vec<String>> {
+        self.vary.get(primary).map(|(names, _)| names.clone())
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<Arc<CachedResponse>> {
+        self.tick += 1;
+        let tick: u64 = self.tick;
+        let (entry, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        self.order.insert(tick, key.to_string());
+        *last_used = tick;
+        Some(entry.clone())
+    }
+
+    pub fn insert(&mut self, entry: Arc<CachedResponse>) {
+        self.remove(&entry.key);
+        if entry.weight() > self.max_bytes {
+            return;
+        }
+
+        self.tick += 1;
+        self.bytes += entry.weight();
+        self.order.insert(self.tick, entry.key.clone());
+        let held: &mut (Vec<String>, usize) = self.vary.entry(primary(&entry.key).to_string()).or_default();
+        held.0 = entry.vary.clone();
+        held.1 += 1;
+        self.entries.insert(entry.key.clone(), (entry, self.tick));
+
+        while self.bytes > self.max_bytes {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            self.remove(&key);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((entry, last_used)) = self.entries.remove(key) {
+            self.order.remove(&last_used);
+            self.bytes -= entry.weight();
+            let primary: &str = primary(key);
+            if let Some(held) = self.vary.get_mut(primary) {
+                held.1 -= 1;
+                if held.1 == 0 {
+                    self.vary.remove(primary);
+                }
+            }
+        }
+    }
+}
+
+/// Sizes of the files in the cache directory, evicted least recently used first once
+/// together they pass the limit.
+struct DiskTier {
+    files: HashMap<PathBuf, (u64, u64)>,
+    order: BTreeMap<u64, PathBuf>,
+    bytes: u64,
+    max_bytes: u64,
+    tick: u64,
+}
+
+impl DiskTier {
+    /// Picks up what earlier runs left in `dir`, oldest first, evicting anything over the limit.
+    fn scan(dir: Option<&Path>, max_bytes: u64) -> DiskTier {
+        let mut tier: DiskTier = DiskTier {
+            files: HashMap::new(),
+            order: BTreeMap::new(),
+            bytes: 0,
+            max_bytes,
+            tick: 0,
+        };
+        let Some(entries) = dir.and_then(|dir: &Path| std::fs::read_dir(dir).ok()) else {
+            return tier;
+        };
+
+        let mut found: Vec<(SystemTime, PathBuf, u64)> = entries
+            .filter_map(|entry: std::io::Result<std::fs::DirEntry>| {
+                let entry: std::fs::DirEntry = entry.ok()?;
+                let path: PathBuf = entry.path();
+                if !matches!(path.extension().and_then(|ext| ext.to_str()), Some("entry" | "vary")) {
+                    return None;
+                }
+                let meta: std::fs::Metadata = entry.metadata().ok()?;
+                Some((meta.modified().unwrap_or(UNIX_EPOCH), path, meta.len()))
+            })
+            .collect();
+        found.sort();
+        for (_, path, size) in found {
+            for evicted in tier.insert(path, size) {
+                if let Err(e) = std::fs::remove_file(&evicted) {
+                    tracing::debug!("Cache: could not evict {}: {}", evicted.display(), e);
+                }
+            }
+        }
+        tier
+    }
+
+    fn touch(&mut self, path: &Path) {
+        self.tick += 1;
+        let tick: u64 = self.tick;
+        let Some((_, last_used)) = self.files.get_mut(path) else {
+            return;
+        };
+        self.order.remove(last_used);
+        self.order.insert(tick, path.to_path_buf());
+        *last_used = tick;
+    }
+
+    /// Records a written file and returns the ones to delete to get back under the limit.
+    fn insert(&mut self, path: PathBuf, size: u64) -> Vec<PathBuf> {
+        if let Some((old_size, last_used)) = self.files.remove(&path) {
+            self.order.remove(&last_used);
+            self.bytes -= old_size;
+        }
+
+        self.tick += 1;
+        self.bytes += size;
+        self.order.insert(self.tick, path.clone());
+        self.files.insert(path, (size, self.tick));
+
+        let mut evicted: Vec<PathBuf> = Vec::new();
+        while self.bytes > self.max_bytes {
+            let Some((_, path)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((size, _)) = self.files.remove(&path) {
+                self.bytes -= size;
+            }
+            evicted.push(path);
+        }
+        evicted
+    }
+}
+
+/// Parses `Cache-Control` into lowercase directive names and optional values.
+fn directives(headers: &HeaderMap) -> HashMap<String, Option<String>> {
+    headers
+        .get_all("cache-control")
+        .iter()
+        .filter_map(|v: &HeaderValue| v.to_str().ok())
+        .flat_map(|v: &str| v.split(','))
+        .filter_map(|directive: &str| {
+            let directive: &str = directive.trim();
+            if directive.is_empty() {
+                return None;
+            }
+            Some(match directive.split_once('=') {
+                Some((name, value)) => (name.trim().to_ascii_lowercase(), Some(value.trim().trim_matches('"').to_string())),
+                None => (directive.to_ascii_lowercase(), None),
+            })
+        })
+        .collect()
+}
+
+fn vary_names(headers: &HeaderMap) -> Vec<String> {
+    let mut names: Vec<String> = headers
+        .get_all("vary")
+        .iter()
+        .filter_map(|v: &HeaderValue| v.to_str().ok())
+        .flat_map(|v: &str| v.split(','))
+        .map(|name: &str| name.trim().to_ascii_lowercase())
+        .filter(|name: &String| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// The primary key a full key was built from.
+fn primary(key: &str) -> &str {
+    key.split('\n').next().unwrap_or(key)
+}
+
+fn full_key(primary: &str, vary: &[String], req_headers: &HeaderMap) -> String {
+    let mut key: String = primary.to_string();
+    for name in vary {
+        let value: String = req_headers
+            .get_all(name.as_str())
+            .iter()
+            .filter_map(|v: &HeaderValue| v.to_str().ok())
+            .collect::<Vec<&str>>()
+            .join(",");
+        key.push('\n');
+        key.push_str(name);
+        key.push(':');
+        key.push_str(&value);
+    }
+    key
+}
+
+fn header_date(headers: &HeaderMap, name: &str) -> Option<SystemTime> {
+    headers
+        .get(name)
+        .and_then(|v: &HeaderValue| v.to_str().ok())
+        .and_then(|v: &str| httpdate::parse_http_date(v).ok())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d: Duration| d.as_secs()).unwrap_or(0)
+}
+
+/// Stable across builds, unlike `DefaultHasher`, so disk entries survive upgrades.
+fn fnv1a(value: &str) -> u64 {
+    value.bytes().fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte: u8| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+async fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let nanos: u32 = SystemTime::now().duration_since(UNIX_EPOCH).map(|d: Duration| d.subsec_nanos()).unwrap_or(0);
+    let tmp: PathBuf = path.with_extension(format!("tmp{}", nanos));
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers: HeaderMap = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn cache(disk_dir: Option<PathBuf>, max_disk_bytes: u64) -> Cache {
+        Cache::new(CacheConfig {
+            max_memory_bytes: 1024 * 1024,
+            max_object_bytes: 1024 * 1024,
+            disk_dir,
+            max_disk_bytes,
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+        })
+    }
+
+    fn entry(key: &str, vary: &[&str], body: &'static str) -> Arc<CachedResponse> {
+        let mut entry: CachedResponse = CachedResponse::new(key.to_string(), StatusCode::OK, &HeaderMap::new(), Bytes::from_static(body.as_bytes()));
+        entry.vary = vary.iter().map(|name: &&str| name.to_string()).collect();
+        Arc::new(entry)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir: PathBuf = std::env::temp_dir().join(format!("cache-test-{}-{}", name, fastrand::u64(..)));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn range_requests_bypass_the_cache() {
+        assert!(Cache::is_cacheable_request(&Method::GET, &HeaderMap::new()));
+        assert!(!Cache::is_cacheable_request(&Method::GET, &headers(&[("range", "bytes=0-99")])));
+        assert!(!Cache::is_cacheable_request(&Method::GET, &headers(&[("authorization", "Bearer x")])));
+        assert!(!Cache::is_cacheable_request(&Method::GET, &headers(&[("cache-control", "no-store")])));
+        assert!(!Cache::is_cacheable_request(&Method::POST, &HeaderMap::new()));
+    }
+
+    #[test]
+    fn partial_content_is_never_stored() {
+        let cache: Cache = cache(None, 0);
+        let fresh: HeaderMap = headers(&[("cache-control", "max-age=60")]);
+        assert!(cache.policy(StatusCode::OK, &fresh).is_some());
+        assert!(cache.policy(StatusCode::PARTIAL_CONTENT, &fresh).is_none());
+
+        let ranged: HeaderMap = headers(&[("cache-control", "max-age=60"), ("content-range", "bytes 0-99/1000")]);
+        assert!(cache.policy(StatusCode::OK, &ranged).is_none());
+    }
+
+    #[test]
+    fn private_and_cookie_responses_are_not_stored() {
+        let cache: Cache = cache(None, 0);
+        assert!(cache.policy(StatusCode::OK, &headers(&[("cache-control", "private, max-age=60")])).is_none());
+        assert!(cache.policy(StatusCode::OK, &headers(&[("cache-control", "no-store")])).is_none());
+        assert!(cache.policy(StatusCode::OK, &headers(&[("cache-control", "max-age=60"), ("set-cookie", "a=b")])).is_none());
+        assert!(cache.policy(StatusCode::OK, &headers(&[("cache-control", "max-age=60"), ("vary", "*")])).is_none());
+    }
+
+    #[tokio::test]
+    async fn stored_partial_response_is_not_served() {
+        let cache: Cache = cache(None, 0);
+        let uri: Uri = Uri::from_static("/file.pdf");
+        let primary: String = Cache::primary_key(&Method::GET, "example.com", &uri);
+        let resp: HeaderMap = headers(&[("cache-control", "max-age=60"), ("content-range", "bytes 0-3/10")]);
+
+        cache.store(&primary, &HeaderMap::new(), StatusCode::PARTIAL_CONTENT, &resp, Bytes::from_static(b"%PDF")).await;
+        assert!(cache.lookup(&primary, &HeaderMap::new()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn vary_picks_the_entry_for_the_request() {
+        let cache: Cache = cache(None, 0);
+        let uri: Uri = Uri::from_static("/");
+        let primary: String = Cache::primary_key(&Method::GET, "example.com", &uri);
+        let resp: HeaderMap = headers(&[("cache-control", "max-age=60"), ("vary", "Accept-Language")]);
+
+        cache.store(&primary, &headers(&[("accept-language", "de")]), StatusCode::OK, &resp, Bytes::from_static(b"hallo")).await;
+        cache.store(&primary, &headers(&[("accept-language", "en")]), StatusCode::OK, &resp, Bytes::from_static(b"hello")).await;
+
+        let de: Arc<CachedResponse> = cache.lookup(&primary, &headers(&[("accept-language", "de")])).await.unwrap();
+        assert_eq!(de.body, Bytes::from_static(b"hallo"));
+        assert!(cache.lookup(&primary, &headers(&[("accept-language", "fr")])).await.is_none());
+    }
+
+    #[test]
+    fn vary_names_are_dropped_with_their_last_entry() {
+        let mut tier: MemoryTier = MemoryTier::new(1024);
+        tier.insert(entry("GET example.com/\naccept-language:de", &["accept-language"], "hallo"));
+        tier.insert(entry("GET example.com/\naccept-language:en", &["accept-language"], "hello"));
+        assert_eq!(tier.vary("GET example.com/"), Some(vec!["accept-language".to_string()]));
+
+        tier.remove("GET example.com/\naccept-language:de");
+        assert!(tier.vary("GET example.com/").is_some());
+        tier.remove("GET example.com/\naccept-language:en");
+        assert!(tier.vary("GET example.com/").is_none());
+    }
+
+    #[test]
+    fn vary_names_are_dropped_on_eviction() {
+        let mut tier: MemoryTier = MemoryTier::new(200);
+        for i in 0..100 {
+            tier.insert(entry(&format!("GET example.com/{}", i), &[], "body"));
+        }
+        assert!(tier.vary.len() <= tier.entries.len());
+        assert!(tier.vary("GET example.com/0").is_none());
+        assert!(tier.vary("GET example.com/99").is_some());
+    }
+
+    #[test]
+    fn disk_tier_evicts_least_recently_used() {
+        let mut tier: DiskTier = DiskTier::scan(None, 100);
+        assert!(tier.insert(PathBuf::from("a"), 40).is_empty());
+        assert!(tier.insert(PathBuf::from("b"), 40).is_empty());
+        tier.touch(Path::new("a"));
+        assert_eq!(tier.insert(PathBuf::from("c"), 40), vec![PathBuf::from("b")]);
+        assert_eq!(tier.bytes, 80);
+
+        // Rewriting a file replaces its size rather than adding to it.
+        assert!(tier.insert(PathBuf::from("c"), 50).is_empty());
+        assert_eq!(tier.bytes, 90);
+    }
+
+    #[tokio::test]
+    async fn disk_cache_stays_under_its_limit() {
+        let dir: PathBuf = temp_dir("limit");
+        let cache: Cache = cache(Some(dir.clone()), 4096);
+        let resp: HeaderMap = headers(&[("cache-control", "max-age=60")]);
+        let body: Bytes = Bytes::from(vec![b'x'; 1000]);
+        for i in 0..20 {
+            let uri: Uri = format!("/{}", i).parse().unwrap();
+            let primary: String = Cache::primary_key(&Method::GET, "example.com", &uri);
+            cache.store(&primary, &HeaderMap::new(), StatusCode::OK, &resp, body.clone()).await;
+        }
+
+        let used: u64 = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry: std::io::Result<std::fs::DirEntry>| entry.unwrap().metadata().unwrap().len())
+            .sum();
+        assert!(used <= 4096, "{} bytes on disk", used);
+
+        // A restart picks up what is there and keeps enforcing the limit.
+        let restarted: DiskTier = DiskTier::scan(Some(&dir), 2048);
+        assert!(restarted.bytes <= 2048);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
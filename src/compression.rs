@@ -0,0 +1,286 @@
+//! Response compression negotiated with each client.
+//!
+//! reqwest negotiates its own encoding with the upstream and hands over decoded bodies, which
+//! the rewriters need anyway. The finished response is compressed here with whichever of
+//! brotli, zstd and gzip the client's `Accept-Encoding` prefers. Media that is compressed
+//! already, bodies under the minimum size and `no-transform` responses go out as they are.
+//! The compressed form of a response served from the cache or stale store is kept, keyed by
+//! the stored body, so a hit is not compressed again.
+
+use crate::cache::{self, CachedResponse, MemoryTier};
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use async_compression::Level;
+use axum::body::{Body, Bytes};
+use axum::extract::{Request, State};
+use axum::http::header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, VARY};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use futures_util::stream::{BoxStream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+pub enum Encoding {
+    #[serde(rename = "br")]
+    Brotli,
+    #[serde(rename = "zstd")]
+    Zstd,
+    #[serde(rename = "gzip")]
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" => Some(Encoding::Gzip),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a comma separated list such as `br,gzip`. `none` turns compression off.
+pub fn parse_encodings(value: &str) -> Result<Vec<Encoding>, String> {
+    let value: &str = value.trim();
+    if value.eq_ignore_ascii_case("none") || value.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    value
+        .split(',')
+        .map(|name: &str| {
+            let name: String = name.trim().to_ascii_lowercase();
+            Encoding::from_name(&name).ok_or_else(|| format!("unknown encoding '{}' (use br, zstd or gzip)", name))
+        })
+        .collect()
+}
+
+pub struct Settings {
+    /// Offered in this order when a client accepts several equally.
+    pub encodings: Vec<Encoding>,
+    pub brotli_level: i32,
+    pub zstd_level: i32,
+    pub gzip_level: i32,
+    /// Smaller bodies are sent uncompressed.
+    pub min_size: usize,
+    /// Memory for compressed forms of stored responses; 0 keeps none.
+    pub variant_memory_bytes: usize,
+}
+
+pub struct Compression {
+    settings: Settings,
+    variants: Mutex<MemoryTier>,
+}
+
+/// Marks a response whose body comes from a stored entry, so its compressed form can be kept.
+#[derive(Clone)]
+pub struct StoredBody {
+    pub key: String,
+    pub body_id: u64,
+}
+
+impl Compression {
+    pub fn new(settings: Settings) -> Compression {
+        Compression {
+            variants: Mutex::new(MemoryTier::new(settings.variant_memory_bytes)),
+            settings,
+        }
+    }
+
+    /// The encoding with the highest `q` the client gave, or `None` for identity.
+    fn negotiate(&self, headers: &HeaderMap) -> Option<Encoding> {
+        let offered: Vec<(String, f32)> = headers
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value: &HeaderValue| value.to_str().ok())
+            .flat_map(|value: &str| value.split(','))
+            .filter_map(|item: &str| {
+                let mut params = item.split(';');
+                let name: String = params.next()?.trim().to_ascii_lowercase();
+                let q: f32 = params
+                    .filter_map(|param: &str| param.trim().strip_prefix("q="))
+                    .find_map(|q: &str| q.trim().parse().ok())
+                    .unwrap_or(1.0);
+                (!name.is_empty()).then_some((if name == "x-gzip" { "gzip".to_string() } else { name }, q))
+            })
+            .collect();
+
+        let quality = |name: &str| -> f32 {
+            offered
+                .iter()
+                .find(|(offered, _)| offered == name)
+                .or_else(|| offered.iter().find(|(offered, _)| offered == "*"))
+                .map(|(_, q)| *q)
+                .unwrap_or(0.0)
+        };
+
+        let mut best: Option<(Encoding, f32)> = None;
+        for encoding in &self.settings.encodings {
+            let q: f32 = quality(encoding.as_str());
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((*encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    fn encode(&self, encoding: Encoding, body: BoxStream<'static, Result<Bytes, axum::Error>>) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
+        let reader = StreamReader::new(body.map_err(std::io::Error::other));
+        match encoding {
+            Encoding::Brotli => ReaderStream::new(BrotliEncoder::with_quality(reader, Level::Precise(self.settings.brotli_level))).boxed(),
+            Encoding::Zstd => ReaderStream::new(ZstdEncoder::with_quality(reader, Level::Precise(self.settings.zstd_level))).boxed(),
+            Encoding::Gzip => ReaderStream::new(GzipEncoder::with_quality(reader, Level::Precise(self.settings.gzip_level))).boxed(),
+        }
+    }
+}
+
+/// Middleware that compresses response bodies for clients that accept it.
+pub async fn compress(State(compression): State<Arc<Compression>>, request: Request, next: Next) -> Response {
+    let accepted: Option<Encoding> = compression.negotiate(request.headers());
+    let is_head: bool = request.method() == Method::HEAD;
+    let response: Response = next.run(request).await;
+    if is_head || compression.settings.encodings.is_empty() || !is_compressible(&response) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    add_vary(&mut parts.headers);
+    let Some(encoding) = accepted else {
+        return Response::from_parts(parts, body);
+    };
+
+    let variant_key: Option<String> = parts
+        .extensions
+        .get::<StoredBody>()
+        .filter(|_| compression.settings.variant_memory_bytes > 0)
+        .map(|stored: &StoredBody| format!("{} {:016x} {}", encoding.as_str(), stored.body_id, stored.key));
+    if let Some(key) = &variant_key {
+        let variant: Option<Arc<CachedResponse>> = compression.variants.lock().unwrap().get(key);
+        if let Some(variant) = variant {
+            mark_encoded(&mut parts.headers, encoding);
+            return Response::from_parts(parts, Body::from(variant.body.clone()));
+        }
+    }
+
+    // Read far enough to know whether the body is worth compressing.
+    let mut data = body.into_data_stream();
+    let mut head: Vec<Result<Bytes, axum::Error>> = Vec::new();
+    let mut buffered: usize = 0;
+    let mut ended: bool = false;
+    while buffered < compression.settings.min_size {
+        match data.next().await {
+            Some(Ok(chunk)) => {
+                buffered += chunk.len();
+                head.push(Ok(chunk));
+            }
+            Some(Err(e)) => {
+                head.push(Err(e));
+                ended = true;
+                break;
+            }
+            None => {
+                ended = true;
+                break;
+            }
+        }
+    }
+    let head = futures_util::stream::iter(head);
+    let body: BoxStream<'static, Result<Bytes, axum::Error>> = if ended { head.boxed() } else { head.chain(data).boxed() };
+    if ended && buffered < compression.settings.min_size {
+        return Response::from_parts(parts, Body::from_stream(body));
+    }
+
+    mark_encoded(&mut parts.headers, encoding);
+    let encoded: BoxStream<'static, Result<Bytes, std::io::Error>> = compression.encode(encoding, body);
+    let body: Body = match variant_key {
+        Some(key) => {
+            let max: usize = compression.settings.variant_memory_bytes;
+            Body::from_stream(cache::tee(encoded, max, move |body: Bytes| {
+                let variant: CachedResponse = CachedResponse::new(key, StatusCode::OK, &HeaderMap::new(), body);
+                compression.variants.lock().unwrap().insert(Arc::new(variant));
+            }))
+        }
+        None => Body::from_stream(encoded),
+    };
+    Response::from_parts(parts, body)
+}
+
+/// Whether the response could be compressed for a client that accepts it.
+fn is_compressible(response: &Response) -> bool {
+    let headers: &HeaderMap = response.headers();
+    if matches!(response.status(), StatusCode::NO_CONTENT | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED)
+        || response.status().is_informational()
+        || headers.contains_key(CONTENT_ENCODING)
+        || headers.contains_key(CONTENT_RANGE)
+    {
+        return false;
+    }
+
+    let no_transform: bool = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value: &HeaderValue| value.to_str().ok())
+        .flat_map(|value: &str| value.split(','))
+        .any(|directive: &str| directive.trim().eq_ignore_ascii_case("no-transform"));
+    if no_transform {
+        return false;
+    }
+
+    let mime: String = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value: &HeaderValue| value.to_str().ok())
+        .and_then(|value: &str| value.split(';').next())
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    !is_precompressed(&mime)
+}
+
+/// Formats that are compressed already (or of unknown type), where another pass only costs
+/// CPU, plus event streams, which must not be held back by an encoder's buffer.
+fn is_precompressed(mime: &str) -> bool {
+    match mime {
+        "" | "application/octet-stream" | "text/event-stream" => true,
+        "image/svg+xml" | "image/bmp" | "image/x-icon" | "image/vnd.microsoft.icon" => false,
+        "font/woff" | "font/woff2" | "application/font-woff" | "application/pdf" | "application/zip" | "application/gzip"
+        | "application/x-gzip" | "application/zstd" | "application/x-7z-compressed" | "application/x-rar-compressed" => true,
+        _ => mime.starts_with("image/") || mime.starts_with("video/") || mime.starts_with("audio/"),
+    }
+}
+
+fn add_vary(headers: &mut HeaderMap) {
+    let listed: bool = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value: &HeaderValue| value.to_str().ok())
+        .flat_map(|value: &str| value.split(','))
+        .any(|name: &str| name.trim() == "*" || name.trim().eq_ignore_ascii_case("accept-encoding"));
+    if !listed {
+        headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+}
+
+/// The encoded body is a different representation, so a strong `ETag` becomes weak.
+fn mark_encoded(headers: &mut HeaderMap, encoding: Encoding) {
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+    headers.remove(CONTENT_LENGTH);
+    let weak: Option<HeaderValue> = headers
+        .get(ETAG)
+        .and_then(|value: &HeaderValue| value.to_str().ok())
+        .filter(|etag: &&str| !etag.starts_with("W/"))
+        .and_then(|etag: &str| HeaderValue::try_from(format!("W/{}", etag)).ok());
+    if let Some(weak) = weak {
+        headers.insert(ETAG, weak);
+    }
+}
@@ -0,0 +1,1445 @@
+//! Proxy configuration: an optional TOML file, overridden by environment variables.
+//!
+//! Every setting has a key in the file (`cache.memory_mb`) and most keep the environment
+//! variable they had before the file existed (`CACHE_MEMORY_MB`), which wins when set.
+//! Errors name the offending key so a bad deploy points straight at the fix.
+
+use crate::acme::Challenge;
+use crate::compression::{self, Encoding};
+use crate::forwarded::{self, Cidr, ForwardHeaders};
+use crate::origin::{self, ContentKind};
+use crate::logging::{AccessFormat, LogFormat};
+use crate::telemetry::TraceExporter;
+use crate::cors::CorsPolicy;
+use crate::normalize::NormalizePolicy;
+use crate::redirects::RedirectTable;
+use crate::security::SecurityPolicy;
+use crate::routes::{PathMatcher, PathRewrite};
+use axum::http::{HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedirectMode {
+    Www,
+    Root,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub admin: AdminConfig,
+    pub tls: TlsConfig,
+    pub health: HealthConfig,
+    pub log: LogConfig,
+    pub telemetry: TelemetryConfig,
+    pub request_id: RequestIdConfig,
+    pub proxy: ProxyConfig,
+    pub site: SiteConfig,
+    pub upstream: UpstreamConfig,
+    pub rewrite: RewriteConfig,
+    pub headers: HeaderRules,
+    /// CORS for the Webflow site and any route without its own; unset passes the upstream's through.
+    pub cors: Option<CorsPolicy>,
+    pub cache: CacheSection,
+    pub compression: CompressionSection,
+    pub stale: StaleSection,
+    #[serde(deserialize_with = "routes")]
+    pub routes: Vec<RouteConfig>,
+    /// Redirect rules for the `[site]` table; each `[[sites]]` entry has its own.
+    pub redirects: RedirectTable,
+    pub normalize: NormalizePolicy,
+    pub security: SecurityPolicy,
+    #[serde(deserialize_with = "tenants")]
+    pub sites: Vec<TenantConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// One address or a list; each gets its own listener. IPv6 is written `[::]:3000`.
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind: Vec<SocketAddr>,
+    /// How long in-flight requests get to finish after SIGTERM or SIGINT.
+    pub shutdown_grace_secs: u64,
+    /// Streams one HTTP/2 or HTTP/3 connection may have open at once.
+    pub max_concurrent_streams: u64,
+    /// Connections with nothing happening for this long are closed, on every listener.
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: vec![SocketAddr::from(([0, 0, 0, 0], 3000))],
+            shutdown_grace_secs: 30,
+            max_concurrent_streams: 100,
+            idle_timeout_secs: 60,
+        }
+    }
+}
+
+/// The listener for `/metrics`, off unless an address is given.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind: Vec<SocketAddr>,
+}
+
+/// HTTPS listeners terminating TLS in the proxy, off unless an address is given.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind: Vec<SocketAddr>,
+    /// PEM chain and key served when no other certificate matches the requested host.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub certs: Vec<HostCert>,
+    /// How often certificate files are checked for changes.
+    pub reload_secs: u64,
+    /// Offers HTTP/2 to clients that support it.
+    pub http2: bool,
+    /// Also serves HTTP/3 on the UDP port of every `bind` address.
+    pub http3: bool,
+    /// How long clients may remember that HTTP/3 is available.
+    pub alt_svc_max_age_secs: u64,
+    pub acme: AcmeConfig,
+}
+
+impl Default for TlsConfig {
+    fn default() -> TlsConfig {
+        TlsConfig {
+            bind: Vec::new(),
+            cert: None,
+            key: None,
+            certs: Vec::new(),
+            reload_secs: 60,
+            http2: true,
+            http3: false,
+            alt_svc_max_age_secs: 86400,
+            acme: AcmeConfig::default(),
+        }
+    }
+}
+
+/// A certificate for particular hosts; `*.example.com` covers one level of subdomains.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostCert {
+    #[serde(deserialize_with = "one_or_many")]
+    pub hosts: Vec<String>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Certificates issued and renewed automatically, off unless a directory URL is given.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcmeConfig {
+    /// e.g. `https://acme-v02.api.letsencrypt.org/directory`.
+    pub directory: Option<String>,
+    pub email: Option<String>,
+    /// Defaults to every site's hosts that no file certificate covers.
+    pub hosts: Vec<String>,
+    /// Where the account key and issued certificates are kept.
+    pub dir: Option<PathBuf>,
+    /// Extra root certificate for the ACME server's own HTTPS, e.g. a local test CA.
+    pub ca_file: Option<PathBuf>,
+    pub challenge: Challenge,
+    /// Days before expiry that a certificate is renewed.
+    pub renew_days: u64,
+}
+
+impl Default for AcmeConfig {
+    fn default() -> AcmeConfig {
+        AcmeConfig {
+            directory: None,
+            email: None,
+            hosts: Vec::new(),
+            dir: None,
+            ca_file: None,
+            challenge: Challenge::Http01,
+            renew_days: 30,
+        }
+    }
+}
+
+/// Reserved paths answered by the proxy itself, and the upstream probe behind readiness.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    pub liveness_path: String,
+    pub readiness_path: String,
+    /// Requested with GET on every staging origin.
+    pub probe_path: String,
+    pub probe_interval_secs: u64,
+    pub probe_timeout_secs: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> HealthConfig {
+        HealthConfig {
+            liveness_path: "/healthz".to_string(),
+            readiness_path: "/readyz".to_string(),
+            probe_path: "/".to_string(),
+            probe_interval_secs: 10,
+            probe_timeout_secs: 5,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `RUST_LOG` style directives, e.g. `info,webflow_reverse_proxy::cache=debug`.
+    pub level: String,
+    pub format: LogFormat,
+    pub access: AccessFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            access: AccessFormat::Combined,
+        }
+    }
+}
+
+/// Where OpenTelemetry spans go. `endpoint` is the OTLP/HTTP base URL; `/v1/traces` is added.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    pub exporter: TraceExporter,
+    pub endpoint: Option<String>,
+    pub file: Option<PathBuf>,
+    pub service_name: String,
+    /// Share of new traces recorded; requests that arrive with a `traceparent` follow the caller.
+    pub sample_ratio: f64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> TelemetryConfig {
+        TelemetryConfig {
+            exporter: TraceExporter::None,
+            endpoint: None,
+            file: None,
+            service_name: env!("CARGO_PKG_NAME").to_string(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestIdConfig {
+    /// The header the ID is read from, forwarded upstream in and echoed back in.
+    pub header: String,
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> RequestIdConfig {
+        RequestIdConfig {
+            header: "x-request-id".to_string(),
+        }
+    }
+}
+
+/// How far forwarding headers from proxies in front are believed.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub trusted_proxies: Vec<Cidr>,
+    pub force_https: bool,
+    pub forward_headers: ForwardHeaders,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> ProxyConfig {
+        ProxyConfig {
+            trusted_proxies: forwarded::default_trusted(),
+            force_https: false,
+            forward_headers: ForwardHeaders::Both,
+        }
+    }
+}
+
+/// The Webflow site being fronted. `staging_url` and `prod_url` have no defaults.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SiteConfig {
+    pub staging_url: Option<String>,
+    pub prod_url: Option<String>,
+    pub base_url: Option<RedirectMode>,
+}
+
+/// A further Webflow site served by the same process, picked by the request's `Host`.
+/// `rewrite`, `headers` and `cors` fall back to the top-level tables like a route's do.
+pub struct TenantConfig {
+    pub staging_url: String,
+    pub prod_url: String,
+    pub base_url: RedirectMode,
+    pub rewrite: Option<RewriteConfig>,
+    pub headers: HeaderRules,
+    pub cors: Option<CorsPolicy>,
+    pub routes: Vec<RouteConfig>,
+    pub redirects: RedirectTable,
+    /// Replaces the top-level `[normalize]` table for this site when given.
+    pub normalize: Option<NormalizePolicy>,
+    /// Replaces the top-level `[security]` table for this site when given.
+    pub security: Option<SecurityPolicy>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTenant {
+    staging_url: String,
+    prod_url: String,
+    base_url: RedirectMode,
+    rewrite: Option<RewriteConfig>,
+    #[serde(default)]
+    headers: RawHeaderRules,
+    cors: Option<CorsPolicy>,
+    #[serde(default)]
+    routes: Vec<RawRoute>,
+    #[serde(default)]
+    redirects: RedirectTable,
+    normalize: Option<NormalizePolicy>,
+    security: Option<SecurityPolicy>,
+}
+
+impl TenantConfig {
+    /// Builds the `[[sites]]` entry found at `key`, e.g. `sites[1]`.
+    fn compile(raw: RawTenant, key: &str) -> Result<TenantConfig, String> {
+        let routes: Vec<RouteConfig> = raw
+            .routes
+            .into_iter()
+            .enumerate()
+            .map(|(i, route)| RouteConfig::compile(route, &format!("{}.routes[{}]", key, i)))
+            .collect::<Result<Vec<RouteConfig>, String>>()?;
+
+        Ok(TenantConfig {
+            staging_url: raw.staging_url,
+            prod_url: raw.prod_url,
+            base_url: raw.base_url,
+            rewrite: raw.rewrite,
+            headers: HeaderRules::compile(raw.headers, &format!("{}.headers", key))?,
+            cors: raw.cors,
+            routes,
+            redirects: raw.redirects,
+            normalize: raw.normalize,
+            security: raw.security,
+        })
+    }
+}
+
+/// A site with the global settings filled in wherever it has none of its own.
+pub struct SiteSpec<'a> {
+    pub staging_url: &'a str,
+    pub prod_url: &'a str,
+    pub redirect_mode: RedirectMode,
+    pub headers: HeaderRules,
+    pub rewrite: &'a RewriteConfig,
+    pub cors: Option<&'a CorsPolicy>,
+    pub routes: &'a [RouteConfig],
+    pub redirects: &'a RedirectTable,
+    pub normalize: &'a NormalizePolicy,
+    pub security: &'a SecurityPolicy,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// The read timeout between chunks, and the connect and first-byte timeouts unless set.
+    pub timeout_secs: u64,
+    pub connect_timeout_secs: Option<u64>,
+    /// How long to wait for the response headers, on each attempt.
+    pub first_byte_timeout_secs: Option<u64>,
+    /// A limit on the whole exchange including the body, or 0 for none.
+    pub total_timeout_secs: u64,
+    /// Extra attempts for idempotent requests without a body.
+    pub retries: u64,
+    pub retry_backoff_ms: u64,
+    pub retry_backoff_max_ms: u64,
+    /// Consecutive failures that open an upstream's circuit, or 0 to never open it.
+    pub circuit_failures: u64,
+    pub circuit_open_secs: u64,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> UpstreamConfig {
+        UpstreamConfig {
+            timeout_secs: 15,
+            connect_timeout_secs: None,
+            first_byte_timeout_secs: None,
+            total_timeout_secs: 0,
+            retries: 2,
+            retry_backoff_ms: 100,
+            retry_backoff_max_ms: 2000,
+            circuit_failures: 5,
+            circuit_open_secs: 30,
+        }
+    }
+}
+
+impl UpstreamConfig {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs.unwrap_or(self.timeout_secs))
+    }
+
+    pub fn first_byte_timeout(&self) -> Duration {
+        Duration::from_secs(self.first_byte_timeout_secs.unwrap_or(self.timeout_secs))
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RewriteConfig {
+    pub content_types: Vec<ContentKind>,
+    /// Extra literal replacements applied wherever the staging origin is rewritten.
+    pub replace: Vec<Replacement>,
+}
+
+impl Default for RewriteConfig {
+    fn default() -> RewriteConfig {
+        RewriteConfig {
+            content_types: vec![ContentKind::Html, ContentKind::Css, ContentKind::Js, ContentKind::Xml, ContentKind::Text],
+            replace: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Replacement {
+    pub from: String,
+    pub to: String,
+}
+
+/// Headers added to or removed from requests sent upstream and responses sent to clients.
+#[derive(Clone, Default, Deserialize)]
+#[serde(try_from = "RawHeaderRules")]
+pub struct HeaderRules {
+    pub request_set: Vec<(HeaderName, HeaderValue)>,
+    pub request_remove: Vec<HeaderName>,
+    pub response_set: Vec<(HeaderName, HeaderValue)>,
+    pub response_remove: Vec<HeaderName>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawHeaderRules {
+    request_set: BTreeMap<String, String>,
+    request_remove: Vec<String>,
+    response_set: BTreeMap<String, String>,
+    response_remove: Vec<String>,
+}
+
+impl HeaderRules {
+    /// These rules with `overrides` layered on top: its set headers win, removals add up.
+    pub fn merged(&self, overrides: &HeaderRules) -> HeaderRules {
+        let keep = |global: &Vec<(HeaderName, HeaderValue)>, over: &Vec<(HeaderName, HeaderValue)>| {
+            global
+                .iter()
+                .filter(|(name, _)| !over.iter().any(|(other, _)| other == name))
+                .chain(over.iter())
+                .cloned()
+                .collect::<Vec<(HeaderName, HeaderValue)>>()
+        };
+
+        HeaderRules {
+            request_set: keep(&self.request_set, &overrides.request_set),
+            request_remove: self.request_remove.iter().chain(&overrides.request_remove).cloned().collect(),
+            response_set: keep(&self.response_set, &overrides.response_set),
+            response_remove: self.response_remove.iter().chain(&overrides.response_remove).cloned().collect(),
+        }
+    }
+
+    /// Whether an incoming request header is removed or overridden before going upstream.
+    pub fn replaces_request(&self, name: &HeaderName) -> bool {
+        self.request_remove.contains(name) || self.request_set.iter().any(|(set, _)| set == name)
+    }
+
+    /// Whether an upstream response header is removed or overridden before reaching the client.
+    pub fn replaces_response(&self, name: &HeaderName) -> bool {
+        self.response_remove.contains(name) || self.response_set.iter().any(|(set, _)| set == name)
+    }
+}
+
+impl TryFrom<RawHeaderRules> for HeaderRules {
+    type Error = String;
+
+    fn try_from(raw: RawHeaderRules) -> Result<HeaderRules, String> {
+        HeaderRules::compile(raw, "headers")
+    }
+}
+
+impl HeaderRules {
+    /// Checks the header names and values of the table found at `key`, e.g. `routes[3].headers`.
+    fn compile(raw: RawHeaderRules, key: &str) -> Result<HeaderRules, String> {
+        Ok(HeaderRules {
+            request_set: header_pairs(&format!("{}.request_set", key), raw.request_set)?,
+            request_remove: header_names(&format!("{}.request_remove", key), raw.request_remove)?,
+            response_set: header_pairs(&format!("{}.response_set", key), raw.response_set)?,
+            response_remove: header_names(&format!("{}.response_remove", key), raw.response_remove)?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSection {
+    pub memory_mb: u64,
+    pub dir: Option<PathBuf>,
+    /// Space the files in `dir` may take before the least recently used are removed.
+    pub disk_mb: u64,
+    pub max_object_mb: u64,
+    pub stale_while_revalidate: u64,
+    pub stale_if_error: u64,
+}
+
+impl Default for CacheSection {
+    fn default() -> CacheSection {
+        CacheSection {
+            memory_mb: 64,
+            dir: None,
+            disk_mb: 1024,
+            max_object_mb: 8,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionSection {
+    /// In order of preference; empty turns compression off.
+    pub encodings: Vec<Encoding>,
+    pub brotli_level: u64,
+    pub zstd_level: u64,
+    pub gzip_level: u64,
+    /// Bytes below which a body is sent uncompressed.
+    pub min_size: u64,
+    /// Memory for compressed copies of cached and stale responses.
+    pub cache_mb: u64,
+}
+
+impl Default for CompressionSection {
+    fn default() -> CompressionSection {
+        CompressionSection {
+            encodings: vec![Encoding::Brotli, Encoding::Zstd, Encoding::Gzip],
+            brotli_level: 4,
+            zstd_level: 3,
+            gzip_level: 6,
+            min_size: 1024,
+            cache_mb: 32,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StaleSection {
+    pub memory_mb: u64,
+    pub dir: Option<PathBuf>,
+    pub fallback_page: Option<PathBuf>,
+}
+
+impl Default for StaleSection {
+    fn default() -> StaleSection {
+        StaleSection {
+            memory_mb: 32,
+            dir: None,
+            fallback_page: None,
+        }
+    }
+}
+
+/// Sends matching paths to another upstream instead of the Webflow site. Exactly one of
+/// `prefix`, `glob` or `regex` picks the paths.
+pub struct RouteConfig {
+    pub matcher: PathMatcher,
+    pub path_rewrite: PathRewrite,
+    pub upstream: String,
+    pub headers: HeaderRules,
+    pub rewrite: Option<RewriteConfig>,
+    pub cors: Option<CorsPolicy>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRoute {
+    prefix: Option<String>,
+    glob: Option<String>,
+    regex: Option<String>,
+    upstream: String,
+    #[serde(default)]
+    strip_prefix: bool,
+    replace_prefix: Option<String>,
+    /// Replacement template for `regex` routes, e.g. `/posts/$1`.
+    path_rewrite: Option<String>,
+    #[serde(default)]
+    headers: RawHeaderRules,
+    rewrite: Option<RewriteConfig>,
+    cors: Option<CorsPolicy>,
+}
+
+impl RouteConfig {
+    /// Builds the route found at `key`, e.g. `routes[3]` or `sites[1].routes[0]`.
+    fn compile(raw: RawRoute, key: &str) -> Result<RouteConfig, String> {
+        let matcher: PathMatcher = match (&raw.prefix, &raw.glob, &raw.regex) {
+            (Some(prefix), None, None) => PathMatcher::prefix(prefix),
+            (None, Some(glob), None) => PathMatcher::glob(glob),
+            (None, None, Some(regex)) => PathMatcher::regex(regex),
+            _ => Err("needs exactly one of prefix, glob or regex".to_string()),
+        }
+        .map_err(|e: String| format!("{}: {}", key, e))?;
+
+        let path_rewrite: PathRewrite = match (raw.strip_prefix, raw.replace_prefix, raw.path_rewrite) {
+            (false, None, None) => PathRewrite::Keep,
+            (true, None, None) if raw.prefix.is_some() => PathRewrite::StripPrefix,
+            (false, Some(to), None) if raw.prefix.is_some() && to.starts_with('/') => PathRewrite::ReplacePrefix(to),
+            (false, Some(to), None) if raw.prefix.is_some() => {
+                return Err(format!("{}.replace_prefix: must start with '/', got '{}'", key, to));
+            }
+            (false, None, Some(template)) if raw.regex.is_some() => PathRewrite::Regex(template),
+            (true, None, None) | (false, Some(_), None) => {
+                return Err(format!("{}: strip_prefix and replace_prefix need a prefix route", key));
+            }
+            (false, None, Some(_)) => return Err(format!("{}.path_rewrite: needs a regex route", key)),
+            _ => return Err(format!("{}: use only one of strip_prefix, replace_prefix or path_rewrite", key)),
+        };
+
+        check_origin(&format!("{}.upstream", key), &raw.upstream).map_err(|e: ConfigError| e.to_string())?;
+
+        Ok(RouteConfig {
+            matcher,
+            path_rewrite,
+            upstream: raw.upstream,
+            headers: HeaderRules::compile(raw.headers, &format!("{}.headers", key))?,
+            rewrite: raw.rewrite,
+            cors: raw.cors,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    key: String,
+    message: String,
+}
+
+impl ConfigError {
+    fn new(key: impl Into<String>, message: impl Into<String>) -> ConfigError {
+        ConfigError {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+impl Config {
+    /// Reads the file (when given), applies environment overrides and validates the result.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let mut config: Config = match path {
+            Some(path) => {
+                let key: String = path.display().to_string();
+                let raw: String = std::fs::read_to_string(path).map_err(|e| ConfigError::new(&key, e.to_string()))?;
+                toml::from_str(&raw).map_err(|e: toml::de::Error| ConfigError::new(key, e.to_string()))?
+            }
+            None => Config::default(),
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The `[site]` table with top-level `[[routes]]`, which serves any host not claimed by
+    /// an entry in `[[sites]]`. It may be left out entirely once `[[sites]]` is used.
+    pub fn default_site(&self) -> Option<SiteSpec<'_>> {
+        if !self.has_default_site() {
+            return None;
+        }
+
+        Some(SiteSpec {
+            staging_url: self.site.staging_url.as_deref().unwrap_or_default(),
+            prod_url: self.site.prod_url.as_deref().unwrap_or_default(),
+            redirect_mode: self.site.base_url.unwrap_or(RedirectMode::Root),
+            headers: self.headers.clone(),
+            rewrite: &self.rewrite,
+            cors: self.cors.as_ref(),
+            routes: &self.routes,
+            redirects: &self.redirects,
+            normalize: &self.normalize,
+            security: &self.security,
+        })
+    }
+
+    pub fn tenants(&self) -> impl Iterator<Item = SiteSpec<'_>> {
+        self.sites.iter().map(|site: &TenantConfig| SiteSpec {
+            staging_url: &site.staging_url,
+            prod_url: &site.prod_url,
+            redirect_mode: site.base_url,
+            headers: self.headers.merged(&site.headers),
+            rewrite: site.rewrite.as_ref().unwrap_or(&self.rewrite),
+            cors: site.cors.as_ref().or(self.cors.as_ref()),
+            routes: &site.routes,
+            redirects: &site.redirects,
+            normalize: site.normalize.as_ref().unwrap_or(&self.normalize),
+            security: site.security.as_ref().unwrap_or(&self.security),
+        })
+    }
+
+    fn has_default_site(&self) -> bool {
+        self.sites.is_empty() || self.site.staging_url.is_some() || self.site.prod_url.is_some() || self.site.base_url.is_some()
+    }
+
+    /// The hosts given ACME certificates: `tls.acme.hosts`, or else every host a site answers
+    /// on that no file certificate names.
+    pub fn acme_hosts(&self) -> Vec<String> {
+        if !self.tls.acme.hosts.is_empty() {
+            return self.tls.acme.hosts.iter().map(|host: &String| host.to_ascii_lowercase()).collect();
+        }
+
+        let covered: Vec<String> = self
+            .tls
+            .certs
+            .iter()
+            .flat_map(|cert: &HostCert| cert.hosts.iter())
+            .map(|host: &String| host.to_ascii_lowercase())
+            .collect();
+        self.default_site()
+            .into_iter()
+            .chain(self.tenants())
+            .flat_map(|site: SiteSpec| site_hosts(site.prod_url))
+            .filter(|host: &String| !covered.contains(host))
+            .collect()
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(value) = env_string("BIND_ADDRESS") {
+            self.server.bind = env_addrs("BIND_ADDRESS", "server.bind", &value)?;
+        }
+        // Hosting platforms hand out the port to use; it replaces the port on every address.
+        if let Some(port) = env_number("PORT", "server.bind")? {
+            let port: u16 = u16::try_from(port).map_err(|_| env_error("PORT", "server.bind", format!("{} is not a valid port", port)))?;
+            for addr in &mut self.server.bind {
+                addr.set_port(port);
+            }
+        }
+        if let Some(value) = env_number("SHUTDOWN_GRACE_SECS", "server.shutdown_grace_secs")? {
+            self.server.shutdown_grace_secs = value;
+        }
+        if let Some(value) = env_number("MAX_CONCURRENT_STREAMS", "server.max_concurrent_streams")? {
+            self.server.max_concurrent_streams = value;
+        }
+        if let Some(value) = env_number("IDLE_TIMEOUT_SECS", "server.idle_timeout_secs")? {
+            self.server.idle_timeout_secs = value;
+        }
+
+        if let Some(value) = env_string("ADMIN_BIND") {
+            self.admin.bind = env_addrs("ADMIN_BIND", "admin.bind", &value)?;
+        }
+
+        if let Some(value) = env_string("TLS_BIND") {
+            self.tls.bind = env_addrs("TLS_BIND", "tls.bind", &value)?;
+        }
+        if let Some(value) = env_string("TLS_CERT_FILE") {
+            self.tls.cert = Some(PathBuf::from(value));
+        }
+        if let Some(value) = env_string("TLS_KEY_FILE") {
+            self.tls.key = Some(PathBuf::from(value));
+        }
+        if let Some(value) = env_number("TLS_RELOAD_SECS", "tls.reload_secs")? {
+            self.tls.reload_secs = value;
+        }
+        if let Some(value) = env_bool("TLS_HTTP2", "tls.http2")? {
+            self.tls.http2 = value;
+        }
+        if let Some(value) = env_bool("TLS_HTTP3", "tls.http3")? {
+            self.tls.http3 = value;
+        }
+        if let Some(value) = env_number("ALT_SVC_MAX_AGE_SECS", "tls.alt_svc_max_age_secs")? {
+            self.tls.alt_svc_max_age_secs = value;
+        }
+        if let Some(value) = env_string("ACME_DIRECTORY") {
+            self.tls.acme.directory = Some(value);
+        }
+        if let Some(value) = env_string("ACME_EMAIL") {
+            self.tls.acme.email = Some(value);
+        }
+        if let Ok(value) = std::env::var("ACME_HOSTS") {
+            self.tls.acme.hosts = value
+                .split(',')
+                .map(|host: &str| host.trim().to_string())
+                .filter(|host: &String| !host.is_empty())
+                .collect();
+        }
+        if let Some(value) = env_string("ACME_DIR") {
+            self.tls.acme.dir = Some(PathBuf::from(value));
+        }
+        if let Some(value) = env_string("ACME_CA_FILE") {
+            self.tls.acme.ca_file = Some(PathBuf::from(value));
+        }
+        if let Some(value) = env_string("ACME_CHALLENGE") {
+            self.tls.acme.challenge = match value.to_ascii_lowercase().as_str() {
+                "http-01" => Challenge::Http01,
+                "tls-alpn-01" => Challenge::TlsAlpn01,
+                other => {
+                    return Err(env_error("ACME_CHALLENGE", "tls.acme.challenge", format!("must be http-01 or tls-alpn-01, got '{}'", other)));
+                }
+            };
+        }
+        if let Some(value) = env_number("ACME_RENEW_DAYS", "tls.acme.renew_days")? {
+            self.tls.acme.renew_days = value;
+        }
+
+        if let Some(value) = env_string("LIVENESS_PATH") {
+            self.health.liveness_path = value;
+        }
+        if let Some(value) = env_string("READINESS_PATH") {
+            self.health.readiness_path = value;
+        }
+        if let Some(value) = env_string("PROBE_PATH") {
+            self.health.probe_path = value;
+        }
+        if let Some(value) = env_number("PROBE_INTERVAL_SECS", "health.probe_interval_secs")? {
+            self.health.probe_interval_secs = value;
+        }
+        if let Some(value) = env_number("PROBE_TIMEOUT_SECS", "health.probe_timeout_secs")? {
+            self.health.probe_timeout_secs = value;
+        }
+
+        if let Some(value) = env_string("LOG_LEVEL").or_else(|| env_string("RUST_LOG")) {
+            self.log.level = value;
+        }
+        if let Some(value) = env_string("LOG_FORMAT") {
+            self.log.format = match value.to_ascii_lowercase().as_str() {
+                "text" => LogFormat::Text,
+                "json" => LogFormat::Json,
+                other => return Err(env_error("LOG_FORMAT", "log.format", format!("must be text or json, got '{}'", other))),
+            };
+        }
+        if let Some(value) = env_string("ACCESS_LOG") {
+            self.log.access = match value.to_ascii_lowercase().as_str() {
+                "json" => AccessFormat::Json,
+                "common" => AccessFormat::Common,
+                "combined" => AccessFormat::Combined,
+                "off" => AccessFormat::Off,
+                other => {
+                    return Err(env_error("ACCESS_LOG", "log.access", format!("must be json, common, combined or off, got '{}'", other)));
+                }
+            };
+        }
+
+        if let Some(value) = env_string("OTEL_TRACES_EXPORTER") {
+            self.telemetry.exporter = match value.to_ascii_lowercase().as_str() {
+                "none" => TraceExporter::None,
+                "otlp" => TraceExporter::Otlp,
+                "file" => TraceExporter::File,
+                other => {
+                    return Err(env_error("OTEL_TRACES_EXPORTER", "telemetry.exporter", format!("must be none, otlp or file, got '{}'", other)));
+                }
+            };
+        }
+        if let Some(value) = env_string("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            self.telemetry.endpoint = Some(value);
+        }
+        if let Some(value) = env_string("OTEL_TRACES_FILE") {
+            self.telemetry.file = Some(PathBuf::from(value));
+        }
+        if let Some(value) = env_string("OTEL_SERVICE_NAME") {
+            self.telemetry.service_name = value;
+        }
+        if let Some(value) = env_string("OTEL_TRACES_SAMPLER_ARG") {
+            self.telemetry.sample_ratio = value.trim().parse().map_err(|_| {
+                env_error("OTEL_TRACES_SAMPLER_ARG", "telemetry.sample_ratio", format!("must be a number, got '{}'", value))
+            })?;
+        }
+
+        if let Some(value) = env_string("REQUEST_ID_HEADER") {
+            self.request_id.header = value;
+        }
+
+        if let Ok(value) = std::env::var("TRUSTED_PROXIES") {
+            self.proxy.trusted_proxies = if value.trim().eq_ignore_ascii_case("none") {
+                Vec::new()
+            } else {
+                value
+                    .split(',')
+                    .filter(|net: &&str| !net.trim().is_empty())
+                    .map(|net: &str| Cidr::parse(net.trim()).map_err(|e: String| env_error("TRUSTED_PROXIES", "proxy.trusted_proxies", e)))
+                    .collect::<Result<Vec<Cidr>, ConfigError>>()?
+            };
+        }
+        if let Some(value) = env_bool("FORCE_HTTPS", "proxy.force_https")? {
+            self.proxy.force_https = value;
+        }
+        if let Some(value) = env_string("FORWARD_HEADERS") {
+            self.proxy.forward_headers = match value.to_ascii_lowercase().as_str() {
+                "x-forwarded" => ForwardHeaders::XForwarded,
+                "forwarded" => ForwardHeaders::Forwarded,
+                "both" => ForwardHeaders::Both,
+                "none" => ForwardHeaders::None,
+                other => {
+                    return Err(env_error(
+                        "FORWARD_HEADERS",
+                        "proxy.forward_headers",
+                        format!("must be x-forwarded, forwarded, both or none, got '{}'", other),
+                    ));
+                }
+            };
+        }
+
+        if let Some(value) = env_string("WEBFLOW_STAGING_URL") {
+            self.site.staging_url = Some(value);
+        }
+        if let Some(value) = env_string("PROD_URL") {
+            self.site.prod_url = Some(value);
+        }
+        if let Some(value) = env_string("BASE_URL") {
+            self.site.base_url = Some(match value.as_str() {
+                "www" => RedirectMode::Www,
+                "root" => RedirectMode::Root,
+                other => return Err(env_error("BASE_URL", "site.base_url", format!("must be 'www' or 'root', got '{}'", other))),
+            });
+        }
+
+        if let Some(value) = env_number("UPSTREAM_TIMEOUT_SECS", "upstream.timeout_secs")? {
+            self.upstream.timeout_secs = value;
+        }
+        if let Some(value) = env_number("UPSTREAM_CONNECT_TIMEOUT_SECS", "upstream.connect_timeout_secs")? {
+            self.upstream.connect_timeout_secs = Some(value);
+        }
+        if let Some(value) = env_number("UPSTREAM_FIRST_BYTE_TIMEOUT_SECS", "upstream.first_byte_timeout_secs")? {
+            self.upstream.first_byte_timeout_secs = Some(value);
+        }
+        if let Some(value) = env_number("UPSTREAM_TOTAL_TIMEOUT_SECS", "upstream.total_timeout_secs")? {
+            self.upstream.total_timeout_secs = value;
+        }
+        if let Some(value) = env_number("UPSTREAM_RETRIES", "upstream.retries")? {
+            self.upstream.retries = value;
+        }
+        if let Some(value) = env_number("UPSTREAM_RETRY_BACKOFF_MS", "upstream.retry_backoff_ms")? {
+            self.upstream.retry_backoff_ms = value;
+        }
+        if let Some(value) = env_number("UPSTREAM_RETRY_BACKOFF_MAX_MS", "upstream.retry_backoff_max_ms")? {
+            self.upstream.retry_backoff_max_ms = value;
+        }
+        if let Some(value) = env_number("UPSTREAM_CIRCUIT_FAILURES", "upstream.circuit_failures")? {
+            self.upstream.circuit_failures = value;
+        }
+        if let Some(value) = env_number("UPSTREAM_CIRCUIT_OPEN_SECS", "upstream.circuit_open_secs")? {
+            self.upstream.circuit_open_secs = value;
+        }
+
+        if let Ok(value) = std::env::var("REWRITE_CONTENT_TYPES") {
+            self.rewrite.content_types =
+                origin::parse_content_kinds(&value).map_err(|e: String| env_error("REWRITE_CONTENT_TYPES", "rewrite.content_types", e))?;
+        }
+
+        if let Some(value) = env_number("CACHE_MEMORY_MB", "cache.memory_mb")? {
+            self.cache.memory_mb = value;
+        }
+        if let Ok(value) = std::env::var("CACHE_DIR") {
+            self.cache.dir = Some(PathBuf::from(value)).filter(|dir: &PathBuf| !dir.as_os_str().is_empty());
+        }
+        if let Some(value) = env_number("CACHE_DISK_MB", "cache.disk_mb")? {
+            self.cache.disk_mb = value;
+        }
+        if let Some(value) = env_number("CACHE_MAX_OBJECT_MB", "cache.max_object_mb")? {
+            self.cache.max_object_mb = value;
+        }
+        if let Some(value) = env_number("CACHE_STALE_WHILE_REVALIDATE", "cache.stale_while_revalidate")? {
+            self.cache.stale_while_revalidate = value;
+        }
+        if let Some(value) = env_number("CACHE_STALE_IF_ERROR", "cache.stale_if_error")? {
+            self.cache.stale_if_error = value;
+        }
+
+        if let Ok(value) = std::env::var("COMPRESSION_ENCODINGS") {
+            self.compression.encodings =
+                compression::parse_encodings(&value).map_err(|e: String| env_error("COMPRESSION_ENCODINGS", "compression.encodings", e))?;
+        }
+        if let Some(value) = env_number("COMPRESSION_BROTLI_LEVEL", "compression.brotli_level")? {
+            self.compression.brotli_level = value;
+        }
+        if let Some(value) = env_number("COMPRESSION_ZSTD_LEVEL", "compression.zstd_level")? {
+            self.compression.zstd_level = value;
+        }
+        if let Some(value) = env_number("COMPRESSION_GZIP_LEVEL", "compression.gzip_level")? {
+            self.compression.gzip_level = value;
+        }
+        if let Some(value) = env_number("COMPRESSION_MIN_SIZE", "compression.min_size")? {
+            self.compression.min_size = value;
+        }
+        if let Some(value) = env_number("COMPRESSION_CACHE_MB", "compression.cache_mb")? {
+            self.compression.cache_mb = value;
+        }
+
+        if let Some(value) = env_number("STALE_MEMORY_MB", "stale.memory_mb")? {
+            self.stale.memory_mb = value;
+        }
+        if let Ok(value) = std::env::var("STALE_DIR") {
+            self.stale.dir = Some(PathBuf::from(value)).filter(|dir: &PathBuf| !dir.as_os_str().is_empty());
+        }
+        if let Ok(value) = std::env::var("FALLBACK_PAGE") {
+            self.stale.fallback_page = Some(PathBuf::from(value)).filter(|path: &PathBuf| !path.as_os_str().is_empty());
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.server.bind.is_empty() {
+            return Err(ConfigError::new("server.bind", "needs at least one address"));
+        }
+
+        for addr in &self.admin.bind {
+            if self.server.bind.iter().any(|server: &SocketAddr| server.port() == addr.port()) {
+                return Err(ConfigError::new("admin.bind", format!("port {} is already used by server.bind", addr.port())));
+            }
+        }
+
+        if !(1..=u64::from(u32::MAX)).contains(&self.server.max_concurrent_streams) {
+            return Err(ConfigError::new("server.max_concurrent_streams", format!("must be between 1 and {}", u32::MAX)));
+        }
+        if self.server.idle_timeout_secs == 0 {
+            return Err(ConfigError::new("server.idle_timeout_secs", "must be at least 1"));
+        }
+
+        for addr in &self.tls.bind {
+            let taken: Option<&str> = if self.server.bind.iter().any(|server: &SocketAddr| server.port() == addr.port()) {
+                Some("server.bind")
+            } else if self.admin.bind.iter().any(|admin: &SocketAddr| admin.port() == addr.port()) {
+                Some("admin.bind")
+            } else {
+                None
+            };
+            if let Some(other) = taken {
+                return Err(ConfigError::new("tls.bind", format!("port {} is already used by {}", addr.port(), other)));
+            }
+        }
+        self.validate_tls()?;
+
+        for (key, path) in [
+            ("health.liveness_path", &self.health.liveness_path),
+            ("health.readiness_path", &self.health.readiness_path),
+            ("health.probe_path", &self.health.probe_path),
+        ] {
+            if !path.starts_with('/') || path.contains(['*', ':', '{', '}']) {
+                return Err(ConfigError::new(key, format!("must be a plain path starting with '/', got '{}'", path)));
+            }
+        }
+        if self.health.liveness_path == self.health.readiness_path {
+            return Err(ConfigError::new("health.readiness_path", "must differ from health.liveness_path"));
+        }
+        if self.health.probe_interval_secs == 0 {
+            return Err(ConfigError::new("health.probe_interval_secs", "must be at least 1"));
+        }
+
+        for (key, secs) in [
+            ("upstream.timeout_secs", Some(self.upstream.timeout_secs)),
+            ("upstream.connect_timeout_secs", self.upstream.connect_timeout_secs),
+            ("upstream.first_byte_timeout_secs", self.upstream.first_byte_timeout_secs),
+        ] {
+            if secs == Some(0) {
+                return Err(ConfigError::new(key, "must be at least 1"));
+            }
+        }
+        if self.upstream.retries > 10 {
+            return Err(ConfigError::new("upstream.retries", format!("must be at most 10, got {}", self.upstream.retries)));
+        }
+        if self.upstream.retry_backoff_max_ms < self.upstream.retry_backoff_ms {
+            return Err(ConfigError::new("upstream.retry_backoff_max_ms", "must not be below upstream.retry_backoff_ms"));
+        }
+        if self.upstream.circuit_failures > 0 && self.upstream.circuit_open_secs == 0 {
+            return Err(ConfigError::new("upstream.circuit_open_secs", "must be at least 1 while the circuit breaker is on"));
+        }
+
+        if self.cache.dir.is_some() && self.cache.disk_mb == 0 {
+            return Err(ConfigError::new("cache.disk_mb", "must be at least 1 while cache.dir is set"));
+        }
+        if let (Some(cache), Some(stale)) = (&self.cache.dir, &self.stale.dir) {
+            if cache == stale {
+                return Err(ConfigError::new("stale.dir", "must differ from cache.dir, whose entries it would overwrite"));
+            }
+        }
+
+        for (key, level, range) in [
+            ("compression.brotli_level", self.compression.brotli_level, 0..=11),
+            ("compression.zstd_level", self.compression.zstd_level, 1..=22),
+            ("compression.gzip_level", self.compression.gzip_level, 1..=9),
+        ] {
+            if !range.contains(&level) {
+                return Err(ConfigError::new(key, format!("must be between {} and {}, got {}", range.start(), range.end(), level)));
+            }
+        }
+
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log.level) {
+            return Err(ConfigError::new("log.level", e.to_string()));
+        }
+
+        if !(0.0..=1.0).contains(&self.telemetry.sample_ratio) {
+            return Err(ConfigError::new("telemetry.sample_ratio", format!("must be between 0 and 1, got {}", self.telemetry.sample_ratio)));
+        }
+        match self.telemetry.exporter {
+            TraceExporter::Otlp => {
+                if let Some(endpoint) = &self.telemetry.endpoint {
+                    check_origin("telemetry.endpoint", endpoint)?;
+                }
+            }
+            TraceExporter::File if self.telemetry.file.is_none() => {
+                return Err(ConfigError::new("telemetry.file", "is required when exporter is 'file' (or set OTEL_TRACES_FILE)"));
+            }
+            _ => {}
+        }
+
+        if HeaderName::try_from(self.request_id.header.as_str()).is_err() {
+            return Err(ConfigError::new("request_id.header", format!("invalid header name '{}'", self.request_id.header)));
+        }
+
+        let mut hosts: Vec<(String, String)> = Vec::new();
+
+        if self.has_default_site() {
+            match self.site.staging_url.as_deref() {
+                None | Some("") => {
+                    return Err(ConfigError::new("site.staging_url", "is required (or set WEBFLOW_STAGING_URL)"));
+                }
+                Some(url) => check_origin("site.staging_url", url)?,
+            }
+
+            match self.site.prod_url.as_deref() {
+                None | Some("") => return Err(ConfigError::new("site.prod_url", "is required (or set PROD_URL)")),
+                Some(host) => check_host("site.prod_url", host, &mut hosts)?,
+            }
+
+            if self.site.base_url.is_none() {
+                return Err(ConfigError::new("site.base_url", "is required (use 'www' or 'root', or set BASE_URL)"));
+            }
+        }
+
+        for (i, site) in self.sites.iter().enumerate() {
+            check_origin(&format!("sites[{}].staging_url", i), &site.staging_url)?;
+            check_host(&format!("sites[{}].prod_url", i), &site.prod_url, &mut hosts)?;
+            if let Some(rewrite) = &site.rewrite {
+                check_replacements(&format!("sites[{}].rewrite", i), rewrite)?;
+            }
+        }
+
+        check_replacements("rewrite", &self.rewrite)?;
+
+        if let Some(path) = &self.stale.fallback_page {
+            if !path.is_file() {
+                return Err(ConfigError::new("stale.fallback_page", format!("'{}' is not a readable file", path.display())));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_tls(&self) -> Result<(), ConfigError> {
+        let tls: &TlsConfig = &self.tls;
+        if tls.cert.is_some() != tls.key.is_some() {
+            return Err(ConfigError::new(if tls.cert.is_some() { "tls.key" } else { "tls.cert" }, "tls.cert and tls.key must be set together"));
+        }
+        for (i, cert) in tls.certs.iter().enumerate() {
+            if cert.hosts.is_empty() {
+                return Err(ConfigError::new(format!("tls.certs[{}].hosts", i), "needs at least one host"));
+            }
+        }
+        if tls.reload_secs == 0 {
+            return Err(ConfigError::new("tls.reload_secs", "must be at least 1"));
+        }
+        if tls.http3 && tls.bind.is_empty() {
+            return Err(ConfigError::new("tls.http3", "needs tls.bind (or TLS_BIND), whose ports it shares over UDP"));
+        }
+
+        let Some(directory) = &tls.acme.directory else {
+            if !tls.bind.is_empty() && tls.cert.is_none() && tls.certs.is_empty() {
+                return Err(ConfigError::new("tls.bind", "needs a certificate: set tls.cert and tls.key, tls.certs or tls.acme.directory"));
+            }
+            return Ok(());
+        };
+        check_origin("tls.acme.directory", directory)?;
+        if tls.bind.is_empty() {
+            return Err(ConfigError::new("tls.acme.directory", "needs tls.bind (or TLS_BIND) to serve the certificates on"));
+        }
+        if tls.acme.dir.is_none() {
+            return Err(ConfigError::new("tls.acme.dir", "is required with tls.acme.directory (or set ACME_DIR)"));
+        }
+        if self.acme_hosts().is_empty() {
+            return Err(ConfigError::new("tls.acme.hosts", "no hosts left to request certificates for"));
+        }
+        if let Some(path) = &tls.acme.ca_file {
+            if !path.is_file() {
+                return Err(ConfigError::new("tls.acme.ca_file", format!("'{}' is not a readable file", path.display())));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The hosts a site answers on: its production domain with and without `www.`.
+pub fn site_hosts(prod_url: &str) -> [String; 2] {
+    let root: String = prod_url.trim_start_matches("www.").to_ascii_lowercase();
+    [format!("www.{}", root), root]
+}
+
+/// Checks a production domain and that no earlier site already answers on it.
+fn check_host(key: &str, host: &str, seen: &mut Vec<(String, String)>) -> Result<(), ConfigError> {
+    if host.is_empty() || host.contains('/') || host.contains(':') {
+        return Err(ConfigError::new(key, format!("must be a bare host such as example.com, got '{}'", host)));
+    }
+
+    for candidate in site_hosts(host) {
+        if let Some((_, other)) = seen.iter().find(|(seen_host, _)| *seen_host == candidate) {
+            return Err(ConfigError::new(key, format!("'{}' is already served by {}", candidate, other)));
+        }
+        seen.push((candidate, key.to_string()));
+    }
+    Ok(())
+}
+
+fn check_replacements(key: &str, rewrite: &RewriteConfig) -> Result<(), ConfigError> {
+    for (i, replacement) in rewrite.replace.iter().enumerate() {
+        if replacement.from.is_empty() {
+            return Err(ConfigError::new(format!("{}.replace[{}].from", key, i), "must not be empty"));
+        }
+    }
+    Ok(())
+}
+
+fn check_origin(key: &str, url: &str) -> Result<(), ConfigError> {
+    let rest: Option<&str> = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"));
+    match rest {
+        Some(host) if !host.is_empty() => Ok(()),
+        _ => Err(ConfigError::new(key, format!("must be an http(s) origin such as https://site.webflow.io, got '{}'", url))),
+    }
+}
+
+fn header_pairs(key: &str, raw: BTreeMap<String, String>) -> Result<Vec<(HeaderName, HeaderValue)>, String> {
+    raw.into_iter()
+        .map(|(name, value)| {
+            let header: HeaderName = HeaderName::try_from(name.as_str()).map_err(|_| format!("{}: invalid header name '{}'", key, name))?;
+            let value: HeaderValue =
+                HeaderValue::try_from(value.as_str()).map_err(|_| format!("{}.{}: invalid header value '{}'", key, name, value))?;
+            Ok((header, value))
+        })
+        .collect()
+}
+
+fn header_names(key: &str, raw: Vec<String>) -> Result<Vec<HeaderName>, String> {
+    raw.iter()
+        .map(|name: &String| HeaderName::try_from(name.as_str()).map_err(|_| format!("{}: invalid header name '{}'", key, name)))
+        .collect()
+}
+
+/// The top-level `[[routes]]`, with each error naming the route's place in the list.
+fn routes<'de, D>(deserializer: D) -> Result<Vec<RouteConfig>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Vec::<RawRoute>::deserialize(deserializer)?
+        .into_iter()
+        .enumerate()
+        .map(|(i, raw)| RouteConfig::compile(raw, &format!("routes[{}]", i)))
+        .collect::<Result<Vec<RouteConfig>, String>>()
+        .map_err(serde::de::Error::custom)
+}
+
+/// The `[[sites]]` entries, with each error naming the site's place in the list.
+fn tenants<'de, D>(deserializer: D) -> Result<Vec<TenantConfig>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Vec::<RawTenant>::deserialize(deserializer)?
+        .into_iter()
+        .enumerate()
+        .map(|(i, raw)| TenantConfig::compile(raw, &format!("sites[{}]", i)))
+        .collect::<Result<Vec<TenantConfig>, String>>()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Accepts either a single value or a list, so `bind = "0.0.0.0:3000"` still works.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    match OneOrMany::<T>::deserialize(deserializer)? {
+        OneOrMany::One(value) => Ok(vec![value]),
+        OneOrMany::Many(values) => Ok(values),
+    }
+}
+
+fn env_addrs(name: &str, key: &str, value: &str) -> Result<Vec<SocketAddr>, ConfigError> {
+    value
+        .split(',')
+        .map(|addr: &str| {
+            addr.trim()
+                .parse()
+                .map_err(|_| env_error(name, key, format!("'{}' is not an address such as 0.0.0.0:3000", addr.trim())))
+        })
+        .collect()
+}
+
+fn env_string(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value: &String| !value.is_empty())
+}
+
+fn env_number(name: &str, key: &str) -> Result<Option<u64>, ConfigError> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|_| env_error(name, key, format!("must be a whole number, got '{}'", value))),
+        Err(_) => Ok(None),
+    }
+}
+
+fn env_bool(name: &str, key: &str) -> Result<Option<bool>, ConfigError> {
+    match env_string(name).map(|value: String| value.to_ascii_lowercase()).as_deref() {
+        Some("true" | "1" | "yes") => Ok(Some(true)),
+        Some("false" | "0" | "no") => Ok(Some(false)),
+        Some(other) => Err(env_error(name, key, format!("must be true or false, got '{}'", other))),
+        None => Ok(None),
+    }
+}
+
+fn env_error(name: &str, key: &str, message: String) -> ConfigError {
+    ConfigError::new(format!("{} ({})", name, key), message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(raw: &str) -> String {
+        match toml::from_str::<Config>(raw) {
+            Ok(_) => panic!("config should not parse"),
+            Err(e) => e.message().to_string(),
+        }
+    }
+
+    const SITE: &str = r#"
+        [site]
+        staging_url = "https://example.webflow.io"
+        prod_url = "example.com"
+        base_url = "root"
+    "#;
+
+    #[test]
+    fn route_errors_name_the_route() {
+        let raw: &str = r#"
+            [[routes]]
+            prefix = "/api"
+            upstream = "https://api.example.com"
+
+            [[routes]]
+            upstream = "https://blog.example.com"
+        "#;
+        assert_eq!(parse_error(raw), "routes[1]: needs exactly one of prefix, glob or regex");
+
+        let raw: &str = r#"
+            [[routes]]
+            prefix = "/api"
+            upstream = "api.example.com"
+        "#;
+        assert!(parse_error(raw).starts_with("routes[0].upstream: must be an http(s) origin"));
+
+        let raw: &str = r#"
+            [[routes]]
+            glob = "/docs/**"
+            upstream = "https://docs.example.com"
+            path_rewrite = "/$1"
+        "#;
+        assert_eq!(parse_error(raw), "routes[0].path_rewrite: needs a regex route");
+    }
+
+    #[test]
+    fn header_errors_name_their_table() {
+        let raw: &str = r#"
+            [headers.response_set]
+            "bad header" = "x"
+        "#;
+        assert_eq!(parse_error(raw), "headers.response_set: invalid header name 'bad header'");
+
+        let raw: &str = r#"
+            [[routes]]
+            prefix = "/api"
+            upstream = "https://api.example.com"
+
+            [[routes]]
+            prefix = "/blog"
+            upstream = "https://blog.example.com"
+            headers.request_remove = ["bad header"]
+        "#;
+        assert_eq!(parse_error(raw), "routes[1].headers.request_remove: invalid header name 'bad header'");
+    }
+
+    #[test]
+    fn site_errors_name_the_site() {
+        let raw: &str = r#"
+            [[sites]]
+            staging_url = "https://one.webflow.io"
+            prod_url = "one.example.com"
+            base_url = "root"
+
+            [[sites]]
+            staging_url = "https://two.webflow.io"
+            prod_url = "two.example.com"
+            base_url = "root"
+
+            [[sites.routes]]
+            prefix = "api"
+            upstream = "https://api.example.com"
+        "#;
+        assert_eq!(parse_error(raw), "sites[1].routes[0]: prefix must start with '/', got 'api'");
+
+        let raw: &str = r#"
+            [[sites]]
+            staging_url = "https://one.webflow.io"
+            prod_url = "one.example.com"
+            base_url = "root"
+            headers.response_set = { "bad header" = "x" }
+        "#;
+        assert_eq!(parse_error(raw), "sites[0].headers.response_set: invalid header name 'bad header'");
+    }
+
+    #[test]
+    fn cache_and_stale_need_their_own_directories() {
+        let shared: String = format!("{}\n[cache]\ndir = \"/var/cache/proxy\"\n[stale]\ndir = \"/var/cache/proxy\"\n", SITE);
+        let config: Config = toml::from_str(&shared).unwrap();
+        assert!(config.validate().unwrap_err().to_string().starts_with("stale.dir:"));
+
+        let separate: String = format!("{}\n[cache]\ndir = \"/var/cache/proxy\"\n[stale]\ndir = \"/var/lib/proxy\"\n", SITE);
+        let config: Config = toml::from_str(&separate).unwrap();
+        assert!(config.validate().is_ok());
+    }
+}
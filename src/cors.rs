@@ -0,0 +1,237 @@
+//! CORS answered by the proxy, for the routes that set a policy.
+//!
+//! Without a policy a route passes the upstream's CORS headers through untouched. With one,
+//! preflights are answered here and never reach the upstream, and the upstream's own
+//! `Access-Control-*` headers are replaced on every response, so only the listed origins get
+//! access whatever the upstream would have allowed.
+
+use axum::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use axum::http::{Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+
+#[derive(Clone, PartialEq)]
+enum OriginPattern {
+    Any,
+    Exact(String),
+    /// `https://*.example.com` as the scheme and `.example.com`, so the apex is not included.
+    Subdomain { scheme: String, suffix: String },
+}
+
+impl OriginPattern {
+    fn parse(pattern: &str) -> Result<OriginPattern, String> {
+        if pattern == "*" {
+            return Ok(OriginPattern::Any);
+        }
+        let pattern: String = pattern.trim_end_matches('/').to_ascii_lowercase();
+        let Some((scheme, host)) = pattern.split_once("://") else {
+            return Err(format!("origin '{}' needs a scheme, e.g. https://example.com", pattern));
+        };
+        if host.is_empty() || host.contains('/') {
+            return Err(format!("origin '{}' must be a scheme and host, without a path", pattern));
+        }
+        match host.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') && !suffix.contains('*') => Ok(OriginPattern::Subdomain {
+                scheme: format!("{}://", scheme),
+                suffix: suffix.to_string(),
+            }),
+            None if !host.contains('*') => Ok(OriginPattern::Exact(pattern.clone())),
+            _ => Err(format!("origin '{}': '*' may only stand for the subdomains, as in https://*.example.com", pattern)),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(exact) => origin.eq_ignore_ascii_case(exact),
+            OriginPattern::Subdomain { scheme, suffix } => {
+                let origin: String = origin.to_ascii_lowercase();
+                origin
+                    .strip_prefix(scheme.as_str())
+                    .and_then(|host: &str| host.strip_suffix(suffix.as_str()))
+                    .is_some_and(|name: &str| !name.is_empty() && !name.contains(['/', ':', '@']))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawCors {
+    /// Leaves CORS to the upstream, e.g. for a route under a site that sets a policy.
+    passthrough: bool,
+    allowed_origins: Vec<String>,
+    allowed_methods: Vec<String>,
+    /// `*` allows whatever request headers a preflight asks for.
+    allowed_headers: Vec<String>,
+    expose_headers: Vec<String>,
+    allow_credentials: bool,
+    max_age_secs: Option<u64>,
+}
+
+impl Default for RawCors {
+    fn default() -> RawCors {
+        RawCors {
+            passthrough: false,
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "HEAD".to_string(), "POST".to_string()],
+            allowed_headers: Vec::new(),
+            expose_headers: Vec::new(),
+            allow_credentials: false,
+            max_age_secs: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "RawCors")]
+pub struct CorsPolicy {
+    passthrough: bool,
+    origins: Vec<OriginPattern>,
+    methods: Vec<Method>,
+    /// `None` reflects the requested headers.
+    headers: Option<Vec<HeaderName>>,
+    expose_headers: Vec<HeaderName>,
+    allow_credentials: bool,
+    max_age_secs: Option<u64>,
+}
+
+impl TryFrom<RawCors> for CorsPolicy {
+    type Error = String;
+
+    fn try_from(raw: RawCors) -> Result<CorsPolicy, String> {
+        let origins: Vec<OriginPattern> = raw
+            .allowed_origins
+            .iter()
+            .map(|origin: &String| OriginPattern::parse(origin))
+            .collect::<Result<Vec<OriginPattern>, String>>()
+            .map_err(|e: String| format!("cors.allowed_origins: {}", e))?;
+        if raw.allow_credentials && origins.contains(&OriginPattern::Any) {
+            return Err("cors: allow_credentials cannot be combined with '*' in allowed_origins; list the origins".to_string());
+        }
+
+        let methods: Vec<Method> = raw
+            .allowed_methods
+            .iter()
+            .map(|method: &String| {
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| format!("cors.allowed_methods: invalid method '{}'", method))
+            })
+            .collect::<Result<Vec<Method>, String>>()?;
+
+        let header_names = |key: &str, names: &[String]| -> Result<Vec<HeaderName>, String> {
+            names
+                .iter()
+                .map(|name: &String| HeaderName::try_from(name.as_str()).map_err(|_| format!("cors.{}: invalid header name '{}'", key, name)))
+                .collect()
+        };
+        let headers: Option<Vec<HeaderName>> = if raw.allowed_headers.iter().any(|name: &String| name == "*") {
+            None
+        } else {
+            Some(header_names("allowed_headers", &raw.allowed_headers)?)
+        };
+
+        Ok(CorsPolicy {
+            passthrough: raw.passthrough,
+            origins,
+            methods,
+            headers,
+            expose_headers: header_names("expose_headers", &raw.expose_headers)?,
+            allow_credentials: raw.allow_credentials,
+            max_age_secs: raw.max_age_secs,
+        })
+    }
+}
+
+impl CorsPolicy {
+    pub fn passthrough(&self) -> bool {
+        self.passthrough
+    }
+
+    /// The `Access-Control-Allow-Origin` value for a request's `Origin`, if it is allowed.
+    fn allow_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        let origin: &HeaderValue = origin?;
+        if self.origins.contains(&OriginPattern::Any) {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let value: &str = origin.to_str().ok()?;
+        self.origins.iter().any(|pattern: &OriginPattern| pattern.matches(value)).then(|| origin.clone())
+    }
+
+    /// Answers a preflight, or `None` when the request is not one. Origins or methods that
+    /// are not allowed get an empty answer, which the browser treats as a refusal.
+    pub fn preflight(&self, method: &Method, request: &HeaderMap) -> Option<Response> {
+        if method != Method::OPTIONS || !request.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD) {
+            return None;
+        }
+
+        let mut response: Response = StatusCode::NO_CONTENT.into_response();
+        let headers: &mut HeaderMap = response.headers_mut();
+        headers.insert(
+            header::VARY,
+            HeaderValue::from_static("origin, access-control-request-method, access-control-request-headers"),
+        );
+
+        let requested: Option<Method> = request
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|value: &HeaderValue| Method::from_bytes(value.as_bytes()).ok());
+        let (Some(allow_origin), Some(requested)) = (self.allow_origin(request.get(header::ORIGIN)), requested) else {
+            return Some(response);
+        };
+        if !self.methods.contains(&requested) {
+            return Some(response);
+        }
+
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        let methods: String = self.methods.iter().map(Method::as_str).collect::<Vec<&str>>().join(", ");
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::try_from(methods).expect("methods are valid header values"));
+        let allow_headers: Option<HeaderValue> = match &self.headers {
+            None => request.get(header::ACCESS_CONTROL_REQUEST_HEADERS).cloned(),
+            Some(names) if names.is_empty() => None,
+            Some(names) => {
+                let names: String = names.iter().map(HeaderName::as_str).collect::<Vec<&str>>().join(", ");
+                Some(HeaderValue::try_from(names).expect("header names are valid header values"))
+            }
+        };
+        if let Some(allow_headers) = allow_headers {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+        if self.allow_credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        if let Some(max_age) = self.max_age_secs {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+        }
+        Some(response)
+    }
+
+    /// Replaces the upstream's CORS headers on a response with this policy's.
+    pub fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        let upstream: Vec<HeaderName> = headers
+            .keys()
+            .filter(|name: &&HeaderName| name.as_str().starts_with("access-control-"))
+            .cloned()
+            .collect();
+        for name in upstream {
+            headers.remove(name);
+        }
+
+        let allow_origin: Option<HeaderValue> = self.allow_origin(origin);
+        // Anything but a bare `*` depends on the request's Origin, which shared caches must know.
+        if allow_origin.as_ref().is_none_or(|value: &HeaderValue| value != "*") {
+            headers.append(header::VARY, HeaderValue::from_static("origin"));
+        }
+        let Some(allow_origin) = allow_origin else {
+            return;
+        };
+
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if self.allow_credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        if !self.expose_headers.is_empty() {
+            let names: String = self.expose_headers.iter().map(HeaderName::as_str).collect::<Vec<&str>>().join(", ");
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::try_from(names).expect("header names are valid header values"));
+        }
+    }
+}
@@ -0,0 +1,429 @@
+//! The client-facing scheme, host and port of a request, as seen before any proxy in front.
+//!
+//! `Forwarded` and `X-Forwarded-*` are only believed when the connection comes from a
+//! trusted proxy. The hop list is walked from the nearest proxy outwards and stops at the
+//! first address that is not trusted, so a client cannot forge values by sending the
+//! headers itself.
+//!
+//! The same rule applies to what goes upstream: the `X-Forwarded-For` and `Forwarded` chains
+//! a trusted proxy sent are extended with this hop, while those from anyone else are dropped
+//! and started over.
+
+use axum::http::uri::Authority;
+use axum::http::{HeaderMap, HeaderValue, Uri};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+
+/// An address or network such as `10.0.0.0/8`, `192.168.1.10` or `fc00::/7`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| format!("'{}' is not an IP address or network", value))?;
+        let max: u8 = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix: &u8| *prefix <= max)
+                .ok_or_else(|| format!("'{}' has an invalid prefix length", value))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask: u32 = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask: u128 = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Cidr, String> {
+        Cidr::parse(&value)
+    }
+}
+
+/// Loopback and private ranges, where platform load balancers usually sit.
+pub fn default_trusted() -> Vec<Cidr> {
+    ["127.0.0.0/8", "10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "::1/128", "fc00::/7"]
+        .iter()
+        .map(|net: &&str| Cidr::parse(net).expect("built-in networks are valid"))
+        .collect()
+}
+
+/// Which forwarding headers are sent upstream.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardHeaders {
+    /// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`.
+    XForwarded,
+    /// RFC 7239 `Forwarded`.
+    Forwarded,
+    Both,
+    /// Neither is added, though untrusted values are still removed.
+    None,
+}
+
+/// Headers replaced by the resolved client-facing values rather than extended.
+const X_FORWARDED_SINGLE: [&str; 3] = ["x-forwarded-proto", "x-forwarded-host", "x-forwarded-port"];
+
+#[derive(Clone)]
+pub struct Forwarding {
+    pub trusted: Vec<Cidr>,
+    pub emit: ForwardHeaders,
+    /// Redirects plain HTTP requests to HTTPS, independently of www/root canonicalization.
+    pub force_https: bool,
+}
+
+impl Forwarding {
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|net: &Cidr| net.contains(ip))
+    }
+}
+
+/// The host this proxy was asked for: the `Host` header, or the `:authority` of an HTTP/2 or
+/// HTTP/3 request. Forwarding headers are left to [`ClientOrigin::from_request`], which only
+/// believes them from a trusted proxy.
+pub fn request_host(headers: &HeaderMap, uri: &Uri) -> String {
+    headers
+        .get("host")
+        .and_then(|value: &HeaderValue| value.to_str().ok())
+        .or_else(|| uri.authority().map(Authority::as_str))
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Where the client thinks it connected to.
+pub struct ClientOrigin {
+    pub scheme: String,
+    /// Lowercased, without the port.
+    pub host: String,
+    pub port: Option<u16>,
+    pub client_ip: IpAddr,
+}
+
+impl ClientOrigin {
+    /// `scheme` is what the connection to this proxy used: `https` on a TLS listener.
+    pub fn from_request(peer: SocketAddr, scheme: &str, host_header: &str, headers: &HeaderMap, forwarding: &Forwarding) -> ClientOrigin {
+        let (host, port) = split_host_port(host_header);
+        let mut origin: ClientOrigin = ClientOrigin {
+            scheme: scheme.to_string(),
+            host,
+            port,
+            client_ip: peer.ip().to_canonical(),
+        };
+
+        if !forwarding.is_trusted(origin.client_ip) {
+            return origin;
+        }
+
+        let hops: Vec<Hop> = if headers.contains_key("forwarded") {
+            forwarded_hops(headers)
+        } else {
+            x_forwarded_hops(headers)
+        };
+
+        // Walk outwards while each hop was itself reported by a trusted proxy.
+        for hop in hops.iter().rev() {
+            if let Some(proto) = &hop.proto {
+                origin.scheme = proto.to_ascii_lowercase();
+            }
+            if let Some(host) = &hop.host {
+                (origin.host, origin.port) = split_host_port(host);
+            }
+            if let Some(port) = hop.port {
+                origin.port = Some(port);
+            }
+            match hop.client {
+                Some(ip) => origin.client_ip = ip,
+                None => break,
+            }
+            if !forwarding.is_trusted(origin.client_ip) {
+                break;
+            }
+        }
+
+        if !matches!(origin.scheme.as_str(), "http" | "https") {
+            origin.scheme = "http".to_string();
+        }
+        origin
+    }
+
+    /// `host[:port]`, leaving out the port when it is the default for the scheme.
+    pub fn authority(&self) -> String {
+        match self.port {
+            Some(port) if !is_default_port(&self.scheme, port) => format!("{}:{}", self.host, port),
+            _ => self.host.clone(),
+        }
+    }
+}
+
+/// Rewrites the forwarding headers of a request about to go upstream. `scheme` and
+/// `host_header` are what this proxy received, and `origin` what was resolved from them.
+pub fn set_upstream_headers(
+    headers: &mut HeaderMap,
+    peer: SocketAddr,
+    scheme: &str,
+    host_header: &str,
+    origin: &ClientOrigin,
+    forwarding: &Forwarding,
+) {
+    let peer_ip: IpAddr = peer.ip().to_canonical();
+    let trusted: bool = forwarding.is_trusted(peer_ip);
+    let x_forwarded: bool = matches!(forwarding.emit, ForwardHeaders::XForwarded | ForwardHeaders::Both);
+    let forwarded: bool = matches!(forwarding.emit, ForwardHeaders::Forwarded | ForwardHeaders::Both);
+
+    if x_forwarded || !trusted {
+        let mut clients: Vec<String> = if trusted { header_list(headers, "x-forwarded-for") } else { Vec::new() };
+        headers.remove("x-forwarded-for");
+        for name in X_FORWARDED_SINGLE {
+            headers.remove(name);
+        }
+
+        if x_forwarded {
+            clients.push(peer_ip.to_string());
+            insert_value(headers, "x-forwarded-for", clients.join(", "));
+            insert_value(headers, "x-forwarded-proto", origin.scheme.clone());
+            insert_value(headers, "x-forwarded-host", origin.authority());
+        }
+    }
+
+    if forwarded || !trusted {
+        let mut elements: Vec<String> = if trusted { header_list(headers, "forwarded") } else { Vec::new() };
+        headers.remove("forwarded");
+
+        if forwarded {
+            elements.push(format!("for={};host={};proto={}", forwarded_node(peer_ip), forwarded_value(host_header), scheme));
+            insert_value(headers, "forwarded", elements.join(", "));
+        }
+    }
+}
+
+fn insert_value(headers: &mut HeaderMap, name: &'static str, value: String) {
+    match HeaderValue::try_from(value) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(_) => tracing::debug!("Not forwarding {}: not a valid header value", name),
+    }
+}
+
+/// IPv6 addresses are bracketed and quoted, as RFC 7239 requires.
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+/// A token as is, anything else as a quoted string.
+fn forwarded_value(value: &str) -> String {
+    if !value.is_empty() && value.bytes().all(|b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)) {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn is_default_port(scheme: &str, port: u16) -> bool {
+    matches!((scheme, port), ("http", 80) | ("https", 443))
+}
+
+/// One proxy's view of the request: what it received and who it received it from.
+#[derive(Default)]
+struct Hop {
+    client: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+    port: Option<u16>,
+}
+
+/// RFC 7239 `Forwarded: for=1.2.3.4;proto=https;host=example.com, for=...`.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Hop> {
+    header_list(headers, "forwarded")
+        .into_iter()
+        .map(|element: String| {
+            let mut hop: Hop = Hop::default();
+            for pair in element.split(';') {
+                let Some((key, value)) = pair.split_once('=') else {
+                    continue;
+                };
+                let value: &str = value.trim().trim_matches('"');
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "for" => hop.client = parse_node(value),
+                    "proto" => hop.proto = Some(value.to_string()),
+                    "host" => hop.host = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            hop
+        })
+        .collect()
+}
+
+/// `X-Forwarded-For` lists one address per hop; the proto, host and port headers are
+/// lined up with it from the right, which is how proxies append to them.
+fn x_forwarded_hops(headers: &HeaderMap) -> Vec<Hop> {
+    let clients: Vec<String> = header_list(headers, "x-forwarded-for");
+    let protos: Vec<String> = header_list(headers, "x-forwarded-proto");
+    let hosts: Vec<String> = header_list(headers, "x-forwarded-host");
+    let ports: Vec<String> = header_list(headers, "x-forwarded-port");
+
+    let len: usize = clients.len().max(protos.len()).max(hosts.len()).max(ports.len());
+    let from_right = |list: &Vec<String>, i: usize| -> Option<String> { (len - i <= list.len()).then(|| list[list.len() - (len - i)].clone()) };
+
+    (0..len)
+        .map(|i: usize| Hop {
+            client: from_right(&clients, i).and_then(|value: String| parse_node(&value)),
+            proto: from_right(&protos, i),
+            host: from_right(&hosts, i),
+            port: from_right(&ports, i).and_then(|value: String| value.parse().ok()),
+        })
+        .collect()
+}
+
+/// Every comma separated value across all instances of a header, in order.
+fn header_list(headers: &HeaderMap, name: &str) -> Vec<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value: &HeaderValue| value.to_str().ok())
+        .flat_map(|value: &str| value.split(','))
+        .map(|value: &str| value.trim().to_string())
+        .filter(|value: &String| !value.is_empty())
+        .collect()
+}
+
+/// A node is `1.2.3.4`, `1.2.3.4:5678`, `[::1]` or `[::1]:5678`. Obfuscated names are ignored.
+fn parse_node(value: &str) -> Option<IpAddr> {
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(addr.ip().to_canonical());
+    }
+    value.strip_prefix('[')?.strip_suffix(']')?.parse::<IpAddr>().ok()
+}
+
+/// Splits a `Host` value into the lowercased host and any port, keeping IPv6 literals like
+/// `[::1]:8080` in their brackets.
+pub fn split_host_port(value: &str) -> (String, Option<u16>) {
+    let value: String = value.trim().to_ascii_lowercase();
+    if let Some(rest) = value.strip_prefix('[') {
+        if let Some((host, after)) = rest.split_once(']') {
+            return (format!("[{}]", host), after.strip_prefix(':').and_then(|port: &str| port.parse().ok()));
+        }
+    }
+    match value.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => (host.to_string(), port.parse().ok()),
+        _ => (value, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers: HeaderMap = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn forwarding() -> Forwarding {
+        Forwarding {
+            trusted: default_trusted(),
+            emit: ForwardHeaders::XForwarded,
+            force_https: false,
+        }
+    }
+
+    fn peer(addr: &str) -> SocketAddr {
+        format!("{}:50000", addr).parse().unwrap()
+    }
+
+    #[test]
+    fn request_host_ignores_forwarding_headers() {
+        let spoofed: HeaderMap = headers(&[("host", "example.com"), ("x-forwarded-host", "evil.com"), ("forwarded", "host=evil.com")]);
+        assert_eq!(request_host(&spoofed, &Uri::from_static("/")), "example.com");
+    }
+
+    #[test]
+    fn request_host_falls_back_to_the_authority() {
+        assert_eq!(request_host(&HeaderMap::new(), &Uri::from_static("https://example.com:8443/page")), "example.com:8443");
+        assert_eq!(request_host(&HeaderMap::new(), &Uri::from_static("/page")), "");
+    }
+
+    #[test]
+    fn untrusted_clients_cannot_set_the_origin() {
+        let spoofed: HeaderMap = headers(&[
+            ("x-forwarded-for", "1.2.3.4"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "evil.com"),
+            ("forwarded", "for=1.2.3.4;proto=https;host=evil.com"),
+        ]);
+        let origin: ClientOrigin = ClientOrigin::from_request(peer("203.0.113.7"), "http", "example.com", &spoofed, &forwarding());
+        assert_eq!(origin.host, "example.com");
+        assert_eq!(origin.scheme, "http");
+        assert_eq!(origin.client_ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn trusted_proxies_set_the_origin() {
+        let forwarded: HeaderMap = headers(&[("x-forwarded-for", "203.0.113.7"), ("x-forwarded-proto", "https"), ("x-forwarded-host", "Example.com")]);
+        let origin: ClientOrigin = ClientOrigin::from_request(peer("10.0.0.2"), "http", "internal:3000", &forwarded, &forwarding());
+        assert_eq!(origin.host, "example.com");
+        assert_eq!(origin.scheme, "https");
+        assert_eq!(origin.authority(), "example.com");
+        assert_eq!(origin.client_ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn hops_beyond_the_first_untrusted_address_are_ignored() {
+        // The client forged the first element; the trusted proxy appended the second.
+        let forwarded: HeaderMap = headers(&[("forwarded", "for=10.0.0.9;host=evil.com;proto=http, for=203.0.113.7;host=example.com;proto=https")]);
+        let origin: ClientOrigin = ClientOrigin::from_request(peer("10.0.0.2"), "http", "internal", &forwarded, &forwarding());
+        assert_eq!(origin.host, "example.com");
+        assert_eq!(origin.scheme, "https");
+        assert_eq!(origin.client_ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn untrusted_chains_are_not_sent_upstream() {
+        let mut spoofed: HeaderMap = headers(&[("x-forwarded-for", "1.2.3.4"), ("x-forwarded-host", "evil.com")]);
+        let origin: ClientOrigin = ClientOrigin::from_request(peer("203.0.113.7"), "http", "example.com", &spoofed, &forwarding());
+        set_upstream_headers(&mut spoofed, peer("203.0.113.7"), "http", "example.com", &origin, &forwarding());
+        assert_eq!(spoofed.get("x-forwarded-for").unwrap(), "203.0.113.7");
+        assert_eq!(spoofed.get("x-forwarded-host").unwrap(), "example.com");
+    }
+
+    #[test]
+    fn ipv6_hosts_keep_their_brackets() {
+        assert_eq!(split_host_port("[::1]:8080"), ("[::1]".to_string(), Some(8080)));
+        assert_eq!(split_host_port("[::1]"), ("[::1]".to_string(), None));
+        assert_eq!(split_host_port("Example.com:443"), ("example.com".to_string(), Some(443)));
+    }
+}
@@ -0,0 +1,109 @@
+//! Liveness and readiness endpoints, answered by the proxy itself.
+//!
+//! Liveness only says the process is serving. Readiness reflects a periodic probe of every
+//! site's staging origin: it stays 503 until the first probe round has passed and whenever
+//! the last probe of any origin failed. Any response below 500 counts as reachable.
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use reqwest::Client;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub struct Health {
+    probes: Vec<Probe>,
+    probe_path: String,
+}
+
+struct Probe {
+    origin: String,
+    ok: AtomicBool,
+    error: Mutex<Option<String>>,
+}
+
+impl Health {
+    pub fn new(origins: Vec<String>, probe_path: &str) -> Health {
+        Health {
+            probes: origins
+                .into_iter()
+                .map(|origin: String| Probe {
+                    origin,
+                    ok: AtomicBool::new(false),
+                    error: Mutex::new(Some("not probed yet".to_string())),
+                })
+                .collect(),
+            probe_path: probe_path.to_string(),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.probes.iter().all(|probe: &Probe| probe.ok.load(Ordering::Relaxed))
+    }
+
+    /// Probes every origin now and then once per `interval`, for as long as the process runs.
+    pub fn spawn_probes(self: Arc<Self>, client: Client, interval: Duration, timeout: Duration) {
+        tokio::spawn(async move {
+            let mut ticker: tokio::time::Interval = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                let rounds = self.probes.iter().map(|probe: &Probe| self.probe(probe, &client, timeout));
+                futures_util::future::join_all(rounds).await;
+            }
+        });
+    }
+
+    async fn probe(&self, probe: &Probe, client: &Client, timeout: Duration) {
+        let url: String = format!("{}{}", probe.origin, self.probe_path);
+        let error: Option<String> = match client.get(&url).timeout(timeout).send().await {
+            Ok(response) if !response.status().is_server_error() => None,
+            Ok(response) => Some(format!("responded {}", response.status())),
+            Err(e) => Some(e.to_string()),
+        };
+
+        let was_ok: bool = probe.ok.swap(error.is_none(), Ordering::Relaxed);
+        match (&error, was_ok) {
+            (Some(e), true) => tracing::warn!("Readiness probe of {} failed: {}", url, e),
+            (None, false) => tracing::info!("Readiness probe of {} passed", url),
+            _ => {}
+        }
+        *probe.error.lock().unwrap() = error;
+    }
+}
+
+/// The liveness and readiness routes, to be merged in front of the proxy.
+pub fn router(health: Arc<Health>, liveness_path: &str, readiness_path: &str) -> Router {
+    Router::new()
+        .route(liveness_path, get(live))
+        .route(readiness_path, get(ready))
+        .with_state(health)
+}
+
+async fn live() -> &'static str {
+    "ok\n"
+}
+
+async fn ready(State(health): State<Arc<Health>>) -> Response {
+    let upstreams: Vec<serde_json::Value> = health
+        .probes
+        .iter()
+        .map(|probe: &Probe| {
+            serde_json::json!({
+                "origin": probe.origin,
+                "ok": probe.ok.load(Ordering::Relaxed),
+                "error": *probe.error.lock().unwrap(),
+            })
+        })
+        .collect();
+
+    let (status, label): (StatusCode, &str) = if health.is_ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+    (status, Json(serde_json::json!({ "status": label, "upstreams": upstreams }))).into_response()
+}
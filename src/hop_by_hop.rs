@@ -0,0 +1,110 @@
+//! Hop-by-hop headers (RFC 9110 section 7.6.1) and the `Via` header.
+//!
+//! Hop-by-hop headers describe one connection and are never forwarded, in either direction:
+//! the fixed set below, every `Proxy-*` header, and whatever a message names in its own
+//! `Connection` header.
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Version};
+
+/// Always connection-specific, whether or not `Connection` lists them.
+const ALWAYS: [&str; 6] = ["connection", "keep-alive", "te", "trailer", "transfer-encoding", "upgrade"];
+
+/// Added to `Via` to name this proxy.
+const PSEUDONYM: &str = env!("CARGO_PKG_NAME");
+
+/// The hop-by-hop headers of one message.
+pub struct HopByHop {
+    listed: Vec<HeaderName>,
+}
+
+impl HopByHop {
+    pub fn of(headers: &HeaderMap) -> HopByHop {
+        let listed: Vec<HeaderName> = headers
+            .get_all("connection")
+            .iter()
+            .filter_map(|value: &HeaderValue| value.to_str().ok())
+            .flat_map(|value: &str| value.split(','))
+            .filter_map(|token: &str| HeaderName::try_from(token.trim()).ok())
+            .collect();
+        HopByHop { listed }
+    }
+
+    pub fn contains(&self, name: &HeaderName) -> bool {
+        ALWAYS.contains(&name.as_str()) || name.as_str().starts_with("proxy-") || self.listed.contains(name)
+    }
+}
+
+/// This proxy's `Via` entry for a message received over `version`.
+pub fn via(version: Version) -> HeaderValue {
+    let protocol: &str = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    HeaderValue::try_from(format!("{} {}", protocol, PSEUDONYM)).expect("the crate name is a valid header value")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers: HeaderMap = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn is_hop(hop: &HopByHop, name: &'static str) -> bool {
+        hop.contains(&HeaderName::from_static(name))
+    }
+
+    #[test]
+    fn fixed_set_is_always_hop_by_hop() {
+        let hop: HopByHop = HopByHop::of(&HeaderMap::new());
+        for name in ["connection", "keep-alive", "te", "trailer", "transfer-encoding", "upgrade"] {
+            assert!(is_hop(&hop, name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn proxy_headers_are_hop_by_hop() {
+        let hop: HopByHop = HopByHop::of(&HeaderMap::new());
+        assert!(is_hop(&hop, "proxy-authorization"));
+        assert!(is_hop(&hop, "proxy-authenticate"));
+        assert!(is_hop(&hop, "proxy-connection"));
+    }
+
+    #[test]
+    fn connection_lists_extra_headers() {
+        let hop: HopByHop = HopByHop::of(&headers(&[("connection", "close, X-Secret ,x-other"), ("connection", "x-third")]));
+        assert!(is_hop(&hop, "x-secret"));
+        assert!(is_hop(&hop, "x-other"));
+        assert!(is_hop(&hop, "x-third"));
+        assert!(!is_hop(&hop, "x-kept"));
+    }
+
+    #[test]
+    fn end_to_end_headers_are_kept() {
+        let hop: HopByHop = HopByHop::of(&headers(&[("connection", "keep-alive")]));
+        for name in ["content-type", "cache-control", "authorization", "via", "tes"] {
+            assert!(!is_hop(&hop, name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn unparseable_connection_tokens_are_ignored() {
+        let hop: HopByHop = HopByHop::of(&headers(&[("connection", "bad header, , x-ok")]));
+        assert!(is_hop(&hop, "x-ok"));
+    }
+
+    #[test]
+    fn via_names_the_received_protocol() {
+        assert_eq!(via(Version::HTTP_11), format!("1.1 {}", PSEUDONYM));
+        assert_eq!(via(Version::HTTP_10), format!("1.0 {}", PSEUDONYM));
+        assert_eq!(via(Version::HTTP_2), format!("2 {}", PSEUDONYM));
+    }
+}
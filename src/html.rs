@@ -0,0 +1,171 @@
+//! Streaming HTML rewriting for pages proxied from Webflow.
+//!
+//! Every HTML transform is registered in [`Rewriter::settings`], so a page is tokenized
+//! once and edited chunk by chunk as it arrives from upstream instead of being buffered.
+
+use crate::origin::{ContentKind, OriginRewrite};
+use axum::body::{Body, Bytes};
+use futures_util::{Stream, StreamExt};
+use lol_html::html_content::TextChunk;
+use lol_html::{doc_text, element, send, AsciiCompatibleEncoding, OutputSink};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+pub struct Rewriter {
+    pub prod_url: String,
+    pub origin: OriginRewrite,
+}
+
+impl Rewriter {
+    /// Builds the lol_html settings holding every element and document handler.
+    /// New HTML transforms belong here.
+    fn settings(&self, encoding: AsciiCompatibleEncoding) -> send::Settings<'static, 'static> {
+        let prod_url: String = self.prod_url.clone();
+
+        let settings = send::Settings::new_send()
+            .with_encoding(encoding)
+            .with_adjust_charset_on_meta_tag(true)
+            .with_strict(false)
+            .append_element_content_handler(element!("[data-wf-domain]", move |el| {
+                el.set_attribute("data-wf-domain", &prod_url)?;
+                Ok(())
+            }));
+
+        if !self.origin.applies_to(ContentKind::Html) {
+            return settings;
+        }
+
+        let attr_origin: OriginRewrite = self.origin.clone();
+        let text_origin: OriginRewrite = self.origin.clone();
+        let mut text_node: String = String::new();
+
+        settings
+            .append_element_content_handler(element!("*", move |el| {
+                let rewritten: Vec<(String, String)> = el
+                    .attributes()
+                    .iter()
+                    .filter_map(|attr| {
+                        let value: String = attr.value();
+                        match attr_origin.replace(&value) {
+                            std::borrow::Cow::Owned(new_value) => Some((attr.name(), new_value)),
+                            std::borrow::Cow::Borrowed(_) => None,
+                        }
+                    })
+                    .collect();
+
+                for (name, value) in rewritten {
+                    el.set_attribute(&name, &value)?;
+                }
+                Ok(())
+            }))
+            // Text nodes (inline scripts, JSON-LD, styles) arrive in arbitrary fragments, so
+            // each node is buffered and rewritten as a whole on its last chunk.
+            .append_document_content_handler(doc_text!(move |chunk: &mut TextChunk| {
+                text_node.push_str(chunk.as_str());
+                if chunk.last_in_text_node() {
+                    let node: String = std::mem::take(&mut text_node);
+                    chunk.set_str(text_origin.replace(&node).into_owned());
+                } else {
+                    chunk.set_str(String::new());
+                }
+                Ok(())
+            }))
+    }
+
+    /// Wraps an upstream HTML body in the streaming rewriter. Pages in an encoding the
+    /// tokenizer cannot handle (UTF-16 and friends) are passed through untouched.
+    pub fn rewrite<S, E>(&self, upstream: S, content_type: &str) -> Body
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + Unpin + 'static,
+        E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+    {
+        let encoding: AsciiCompatibleEncoding = match encoding_for(content_type) {
+            Some(encoding) => encoding,
+            None => return Body::from_stream(upstream),
+        };
+
+        let output: Arc<Mutex<Vec<u8>>> = Arc::new(Mutex::new(Vec::new()));
+        let rewriter = send::HtmlRewriter::new(self.settings(encoding), SharedSink(output.clone()));
+
+        // Lives as long as the body, so it covers the whole rewrite rather than its setup.
+        let span: tracing::Span = tracing::info_span!(target: crate::telemetry::SPAN_TARGET, "html_rewrite");
+        let state = RewriteState {
+            upstream,
+            rewriter: Some(rewriter),
+            output,
+            span,
+        };
+
+        Body::from_stream(futures_util::stream::unfold(state, next_chunk))
+    }
+}
+
+/// Resolves the `charset` parameter of a Content-Type header, defaulting to UTF-8.
+/// A `<meta charset>` in the document can still switch the encoding mid-stream.
+fn encoding_for(content_type: &str) -> Option<AsciiCompatibleEncoding> {
+    let charset: Option<&str> = content_type.split(';').skip(1).find_map(|param: &str| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"'))
+    });
+
+    match charset {
+        Some(label) => encoding_rs::Encoding::for_label(label.as_bytes()).and_then(AsciiCompatibleEncoding::new),
+        None => Some(AsciiCompatibleEncoding::utf_8()),
+    }
+}
+
+struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+impl OutputSink for SharedSink {
+    fn handle_chunk(&mut self, chunk: &[u8]) {
+        self.0.lock().unwrap().extend_from_slice(chunk);
+    }
+}
+
+struct RewriteState<S> {
+    upstream: S,
+    rewriter: Option<send::HtmlRewriter<'static, SharedSink>>,
+    output: Arc<Mutex<Vec<u8>>>,
+    span: tracing::Span,
+}
+
+impl<S> RewriteState<S> {
+    fn take_output(&self) -> Bytes {
+        Bytes::from(std::mem::take(&mut *self.output.lock().unwrap()))
+    }
+}
+
+/// Feeds upstream chunks into the rewriter until it produces output, the upstream
+/// ends, or something fails. Errors abort the response body since headers are gone.
+async fn next_chunk<S, E>(mut state: RewriteState<S>) -> Option<(Result<Bytes, std::io::Error>, RewriteState<S>)>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut rewriter = state.rewriter.take()?;
+
+    loop {
+        match state.upstream.next().await {
+            Some(Ok(chunk)) => {
+                if let Err(e) = state.span.in_scope(|| rewriter.write(&chunk)) {
+                    return Some((Err(std::io::Error::other(e)), state));
+                }
+                let out: Bytes = state.take_output();
+                if !out.is_empty() {
+                    state.rewriter = Some(rewriter);
+                    return Some((Ok(out), state));
+                }
+            }
+            Some(Err(e)) => return Some((Err(std::io::Error::other(e)), state)),
+            None => {
+                if let Err(e) = state.span.in_scope(|| rewriter.end()) {
+                    return Some((Err(std::io::Error::other(e)), state));
+                }
+                let out: Bytes = state.take_output();
+                return Some((Ok(out), state));
+            }
+        }
+    }
+}
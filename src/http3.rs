@@ -0,0 +1,186 @@
+//! HTTP/3 over QUIC, on the UDP side of the TLS listeners.
+//!
+//! Browsers only try HTTP/3 after a TLS response advertised it with `Alt-Svc`, so the TCP
+//! listener on the same port always stays up as well. Requests reach the same router as over
+//! TCP, with the same `ConnectInfo` and `Tls` extensions, and the QUIC transport enforces the
+//! stream and idle limits itself.
+
+use crate::server::{self, Limits};
+use axum::body::{Body, Bytes};
+use axum::extract::ConnectInfo;
+use axum::Router;
+use futures_util::stream;
+use hyper::body::Buf;
+use h3::server::RequestStream;
+use http_body_util::BodyExt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio_util::task::TaskTracker;
+use tower::ServiceExt;
+
+/// The ALPN protocol of HTTP/3.
+const ALPN: &[u8] = b"h3";
+
+/// Binds a QUIC endpoint on the UDP port of every address, failing startup if one is taken.
+pub fn bind(addrs: &[SocketAddr], tls: &rustls::ServerConfig, limits: Limits) -> io::Result<Vec<(SocketAddr, quinn::Endpoint)>> {
+    let mut tls: rustls::ServerConfig = tls.clone();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls).map_err(|e| io::Error::other(e.to_string()))?;
+
+    let mut transport: quinn::TransportConfig = quinn::TransportConfig::default();
+    transport.max_concurrent_bidi_streams(limits.max_concurrent_streams.into());
+    transport.max_idle_timeout(Some(limits.idle_timeout.try_into().map_err(|e| io::Error::other(format!("idle timeout: {}", e)))?));
+    let mut config: quinn::ServerConfig = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(Arc::new(transport));
+
+    addrs
+        .iter()
+        .map(|addr: &SocketAddr| {
+            udp_socket(*addr, server::v6_only(*addr, addrs))
+                .and_then(|socket: std::net::UdpSocket| {
+                    quinn::Endpoint::new(quinn::EndpointConfig::default(), Some(config.clone()), socket, Arc::new(quinn::TokioRuntime))
+                })
+                .map(|endpoint: quinn::Endpoint| (*addr, endpoint))
+                .map_err(|e: io::Error| io::Error::new(e.kind(), format!("{} (udp): {}", addr, e)))
+        })
+        .collect()
+}
+
+fn udp_socket(addr: SocketAddr, v6_only: bool) -> io::Result<std::net::UdpSocket> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    if v6_only {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Accepts QUIC connections until shutdown, then waits for the open ones to finish.
+pub async fn serve(endpoint: quinn::Endpoint, app: Router, mut shutdown_rx: watch::Receiver<bool>) {
+    let tracker: TaskTracker = TaskTracker::new();
+
+    loop {
+        let incoming: quinn::Incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            _ = shutdown_rx.wait_for(|stop: &bool| *stop) => break,
+        };
+        tracker.spawn(serve_connection(incoming, app.clone(), tracker.clone(), shutdown_rx.clone()));
+    }
+
+    tracker.close();
+    tracker.wait().await;
+    endpoint.close(0u32.into(), b"shutting down");
+    endpoint.wait_idle().await;
+}
+
+async fn serve_connection(incoming: quinn::Incoming, app: Router, tracker: TaskTracker, mut shutdown_rx: watch::Receiver<bool>) {
+    let peer: SocketAddr = incoming.remote_address();
+    let connection: quinn::Connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            tracing::debug!("QUIC handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+    let mut connection: h3::server::Connection<h3_quinn::Connection, Bytes> = match h3::server::Connection::new(h3_quinn::Connection::new(connection)).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            tracing::debug!("HTTP/3 connection from {} failed: {}", peer, e);
+            return;
+        }
+    };
+
+    let mut closing: bool = false;
+    loop {
+        let accepted = tokio::select! {
+            accepted = connection.accept() => Some(accepted),
+            _ = shutdown_rx.wait_for(|stop: &bool| *stop), if !closing => None,
+        };
+        let Some(accepted) = accepted else {
+            // Sends a GOAWAY; requests already open are still answered.
+            closing = true;
+            if let Err(e) = connection.shutdown(0).await {
+                tracing::debug!("HTTP/3 connection from {} did not close cleanly: {}", peer, e);
+            }
+            continue;
+        };
+        match accepted {
+            Ok(Some(resolver)) => {
+                let app: Router = app.clone();
+                tracker.spawn(async move {
+                    match resolver.resolve_request().await {
+                        Ok((request, stream)) => {
+                            if let Err(e) = serve_request(app, peer, request, stream).await {
+                                tracing::debug!("HTTP/3 request from {} failed: {}", peer, e);
+                            }
+                        }
+                        Err(e) => tracing::debug!("Bad HTTP/3 request from {}: {}", peer, e),
+                    }
+                });
+            }
+            Ok(None) => break,
+            Err(e) => {
+                if !e.is_h3_no_error() {
+                    tracing::debug!("HTTP/3 connection from {} ended: {}", peer, e);
+                }
+                break;
+            }
+        }
+    }
+}
+
+async fn serve_request(
+    app: Router,
+    peer: SocketAddr,
+    request: axum::http::Request<()>,
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+) -> Result<(), h3::error::StreamError> {
+    let (mut send, receive) = stream.split();
+
+    // Ends after the first error, which goes to the handler as a body error.
+    let body = stream::unfold(Some(receive), |receive| async move {
+        let mut receive = receive?;
+        match receive.recv_data().await {
+            Ok(Some(mut data)) => Some((Ok(data.copy_to_bytes(data.remaining())), Some(receive))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    let mut request: axum::extract::Request = request.map(|()| Body::from_stream(body));
+    request.extensions_mut().insert(ConnectInfo(peer));
+    request.extensions_mut().insert(server::Tls);
+
+    let response: axum::response::Response = match app.oneshot(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    };
+    let (parts, mut body) = response.into_parts();
+    send.send_response(axum::http::Response::from_parts(parts, ())).await?;
+
+    while let Some(frame) = body.frame().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                // The response was cut short, e.g. the upstream went away mid-body.
+                tracing::debug!("HTTP/3 response body to {} failed: {}", peer, e);
+                send.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+                return Ok(());
+            }
+        };
+        match frame.into_data() {
+            Ok(data) => send.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    send.send_trailers(trailers).await?;
+                }
+            }
+        }
+    }
+    send.finish().await
+}
+
@@ -0,0 +1,287 @@
+//! Logging setup and the access log.
+//!
+//! Everything goes through `tracing`. Application events are filtered by `log.level` and
+//! printed as text or JSON; access log entries use the `access` target and get a layer of
+//! their own, so they can be JSON objects or raw Common/Combined Log Format lines
+//! regardless of how the rest is printed. Common/Combined lines end with the request ID as an
+//! extra quoted field, and application lines carry it through the request span. OpenTelemetry spans (the `otel` target) go only
+//! to the OpenTelemetry layer, together with warnings and errors as span events.
+
+use crate::request_id::{self, RequestId};
+use crate::telemetry;
+use axum::body::{Body, Bytes};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, HeaderValue, Method, Version};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry_sdk::trace::SdkTracer;
+use serde::Deserialize;
+use std::io::IsTerminal;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+pub const ACCESS_TARGET: &str = "access";
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessFormat {
+    Json,
+    Common,
+    Combined,
+    Off,
+}
+
+/// Installs the global subscriber. `level` takes `RUST_LOG` style directives.
+pub fn init(level: &str, format: LogFormat, access: AccessFormat, tracer: Option<SdkTracer>) {
+    let app_filter = tracing_subscriber::EnvFilter::new(format!(
+        "{},{}=off,{}=off,{}=info",
+        level,
+        ACCESS_TARGET,
+        telemetry::SPAN_TARGET,
+        request_id::SPAN_TARGET
+    ));
+    let app_layer = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().with_ansi(std::io::stdout().is_terminal()).boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().flatten_event(true).boxed(),
+    }
+    .with_filter(app_filter);
+
+    let access_filter = tracing_subscriber::filter::Targets::new().with_target(ACCESS_TARGET, tracing::Level::INFO);
+    let access_layer = match access {
+        AccessFormat::Json => Some(tracing_subscriber::fmt::layer().json().flatten_event(true).with_target(false).boxed()),
+        AccessFormat::Common | AccessFormat::Combined => Some(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .without_time()
+                .with_level(false)
+                .with_target(false)
+                .boxed(),
+        ),
+        AccessFormat::Off => None,
+    }
+    .map(|layer| layer.with_filter(access_filter));
+
+    let otel_filter = tracing_subscriber::filter::Targets::new()
+        .with_target(telemetry::SPAN_TARGET, tracing::Level::INFO)
+        .with_target(env!("CARGO_CRATE_NAME"), tracing::Level::WARN);
+    let otel_layer = tracer.map(|tracer: SdkTracer| tracing_opentelemetry::layer().with_tracer(tracer).with_filter(otel_filter));
+
+    tracing_subscriber::registry().with(app_layer).with(access_layer).with(otel_layer).init();
+}
+
+/// Details only the proxy handler knows, filled in while the request is handled.
+#[derive(Default)]
+pub struct RequestLog {
+    inner: Mutex<RequestDetails>,
+}
+
+#[derive(Default)]
+struct RequestDetails {
+    client_ip: Option<IpAddr>,
+    host: Option<String>,
+    upstream: Option<Duration>,
+}
+
+impl RequestLog {
+    pub fn client(&self, client_ip: IpAddr, host: &str) {
+        let mut details = self.inner.lock().unwrap();
+        details.client_ip = Some(client_ip);
+        details.host = Some(host.to_string());
+    }
+
+    pub fn upstream(&self, latency: Duration) {
+        self.inner.lock().unwrap().upstream = Some(latency);
+    }
+}
+
+/// Middleware that writes one access log entry per request, once the response body has
+/// been sent (or the client went away), so `bytes` and `total_ms` cover the whole transfer.
+pub async fn access_log(
+    State(format): State<AccessFormat>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if format == AccessFormat::Off {
+        return next.run(request).await;
+    }
+
+    let started: Instant = Instant::now();
+    let details: Arc<RequestLog> = Arc::new(RequestLog::default());
+    request.extensions_mut().insert(details.clone());
+
+    let headers: &HeaderMap = request.headers();
+    let mut entry: Entry = Entry {
+        format,
+        started,
+        time: SystemTime::now(),
+        details,
+        peer: peer.ip().to_canonical(),
+        method: request.method().clone(),
+        target: request.uri().path_and_query().map(|pq| pq.as_str().to_string()).unwrap_or_else(|| "/".to_string()),
+        version: request.version(),
+        host: header(headers, "host"),
+        referer: header(headers, "referer"),
+        user_agent: header(headers, "user-agent"),
+        request_id: None,
+        status: 0,
+        cache: None,
+        bytes: 0,
+    };
+
+    let response: Response = next.run(request).await;
+    entry.status = response.status().as_u16();
+    entry.cache = header(response.headers(), "x-cache");
+    entry.request_id = response.extensions().get::<RequestId>().map(|id: &RequestId| id.as_str().to_string());
+
+    let (parts, body) = response.into_parts();
+    Response::from_parts(parts, Body::new(LoggedBody { inner: body, entry }))
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers.get(name).and_then(|v: &HeaderValue| v.to_str().ok()).map(str::to_string)
+}
+
+struct Entry {
+    format: AccessFormat,
+    started: Instant,
+    time: SystemTime,
+    details: Arc<RequestLog>,
+    peer: IpAddr,
+    method: Method,
+    target: String,
+    version: Version,
+    host: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+    status: u16,
+    cache: Option<String>,
+    bytes: u64,
+}
+
+impl Entry {
+    fn write(&self) {
+        let details = self.details.inner.lock().unwrap();
+        let client_ip: IpAddr = details.client_ip.unwrap_or(self.peer);
+        let host: Option<&str> = details.host.as_deref().or(self.host.as_deref());
+        let total_ms: f64 = self.started.elapsed().as_secs_f64() * 1000.0;
+        let upstream_ms: Option<f64> = details.upstream.map(|d: Duration| d.as_secs_f64() * 1000.0);
+        let path: &str = self.target.split('?').next().unwrap_or(&self.target);
+
+        match self.format {
+            AccessFormat::Json => tracing::info!(
+                target: ACCESS_TARGET,
+                method = %self.method,
+                host,
+                path,
+                status = self.status,
+                bytes = self.bytes,
+                upstream_ms,
+                total_ms,
+                cache = self.cache.as_deref(),
+                client_ip = %client_ip,
+                request_id = self.request_id.as_deref(),
+            ),
+            AccessFormat::Common | AccessFormat::Combined => {
+                let mut line: String = format!(
+                    "{} - - [{}] \"{} {} {:?}\" {} {}",
+                    client_ip,
+                    clf_time(self.time),
+                    self.method,
+                    self.target,
+                    self.version,
+                    self.status,
+                    if self.bytes == 0 { "-".to_string() } else { self.bytes.to_string() },
+                );
+                if self.format == AccessFormat::Combined {
+                    line.push_str(&format!(
+                        " \"{}\" \"{}\"",
+                        self.referer.as_deref().unwrap_or("-"),
+                        self.user_agent.as_deref().unwrap_or("-")
+                    ));
+                }
+                line.push_str(&format!(" \"{}\"", self.request_id.as_deref().unwrap_or("-")));
+                tracing::info!(target: ACCESS_TARGET, "{}", line);
+            }
+            AccessFormat::Off => {}
+        }
+    }
+}
+
+/// Counts the bytes of a response body and logs the request when the body is dropped.
+struct LoggedBody {
+    inner: Body,
+    entry: Entry,
+}
+
+impl http_body::Body for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<http_body::Frame<Bytes>, axum::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.entry.bytes += data.len() as u64;
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.entry.write();
+    }
+}
+
+/// `10/Oct/2000:13:55:36 +0000`, always in UTC.
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs: u64 = time.duration_since(UNIX_EPOCH).map(|d: Duration| d.as_secs()).unwrap_or(0);
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z: i64 = days + 719_468;
+    let era: i64 = z.div_euclid(146_097);
+    let doe: i64 = z - era * 146_097;
+    let yoe: i64 = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy: i64 = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp: i64 = (5 * doy + 2) / 153;
+    let day: i64 = doy - (153 * mp + 2) / 5 + 1;
+    let month: i64 = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year: i64 = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
@@ -1,193 +1,939 @@
+use crate::config::RedirectMode;
 use axum::{
     body::Body,
-    extract::{Host, State},
+    extract::{ConnectInfo, State},
+    Extension,
     http::{HeaderMap, StatusCode, Uri},
     response::{IntoResponse, Redirect, Response},
     routing::any,
     Router,
 };
-use http_body_util::BodyExt;
-use regex::Regex;
+use futures_util::stream::{BoxStream, StreamExt, TryStreamExt};
 use reqwest::Client;
 use std::sync::Arc;
-use tower_http::cors::CorsLayer;
 
-#[derive(Clone, PartialEq)]
-enum RedirectMode {
-    Www, 
-    Root,
-}
+mod acme;
+mod admin;
+mod cache;
+mod compression;
+mod config;
+mod cors;
+mod forwarded;
+mod health;
+mod hop_by_hop;
+mod html;
+mod http3;
+mod logging;
+mod metrics;
+mod normalize;
+mod origin;
+mod redirects;
+mod request_id;
+mod routes;
+mod security;
+mod server;
+mod sites;
+mod stale;
+mod telemetry;
+mod tls;
+mod upstream;
 
 #[derive(Clone)]
 struct AppState {
     client: Client,
-    webflow_url: String,
-    prod_url: String,
-    redirect_mode: RedirectMode,
+    cache: Option<Arc<cache::Cache>>,
+    stale: Option<Arc<stale::StaleStore>>,
+    fallback_page: Option<axum::body::Bytes>,
+    sites: sites::SiteTable,
+    forwarding: forwarded::Forwarding,
+    metrics: Arc<metrics::Metrics>,
+    request_id: axum::http::HeaderName,
+    upstream_policy: upstream::Policy,
+    breakers: Arc<upstream::Breakers>,
 }
 
 #[tokio::main]
 async fn main() {
     dotenvy::dotenv().ok();
 
-    let webflow_url: String = match std::env::var("WEBFLOW_STAGING_URL") {
-        Ok(url) => url,
-        Err(_) => {
-            eprintln!("Error: WEBFLOW_STAGING_URL environment variable is not set");
+    let args: Args = parse_args();
+    let config: config::Config = match config::Config::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
             std::process::exit(1);
         }
     };
 
-    let prod_url: String = match std::env::var("PROD_URL") {
-        Ok(url) => url,
-        Err(_) => {
-            eprintln!("Error: PROD_URL environment variable is not set");
+    if args.check_config {
+        println!("Configuration OK");
+        return;
+    }
+
+    let tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider> = match telemetry::init(telemetry::Settings {
+        exporter: config.telemetry.exporter,
+        endpoint: config.telemetry.endpoint.as_deref(),
+        file: config.telemetry.file.as_deref(),
+        service_name: &config.telemetry.service_name,
+        sample_ratio: config.telemetry.sample_ratio,
+    }) {
+        Ok(provider) => provider,
+        Err(e) => {
+            eprintln!("Error: {}", e);
             std::process::exit(1);
         }
     };
 
-    let redirect_mode = match std::env::var("BASE_URL").as_deref() {
-        Ok("www") => RedirectMode::Www,
-        Ok("root") => RedirectMode::Root,
-        Ok(other) => {
-            eprintln!("Error: BASE_URL must be 'www' or 'root', got '{}'", other);
+    logging::init(
+        &config.log.level,
+        config.log.format,
+        config.log.access,
+        tracer_provider.as_ref().map(telemetry::tracer),
+    );
+
+    let sites: sites::SiteTable = sites::SiteTable::new(&config);
+
+    let cache_config = cache::CacheConfig {
+        max_memory_bytes: config.cache.memory_mb as usize * 1024 * 1024,
+        max_object_bytes: config.cache.max_object_mb as usize * 1024 * 1024,
+        disk_dir: config.cache.dir.clone(),
+        max_disk_bytes: config.cache.disk_mb * 1024 * 1024,
+        stale_while_revalidate: std::time::Duration::from_secs(config.cache.stale_while_revalidate),
+        stale_if_error: std::time::Duration::from_secs(config.cache.stale_if_error),
+    };
+    let cache: Option<Arc<cache::Cache>> = (cache_config.max_memory_bytes > 0 || cache_config.disk_dir.is_some())
+        .then(|| Arc::new(cache::Cache::new(cache_config)));
+
+    let compression: Arc<compression::Compression> = Arc::new(compression::Compression::new(compression::Settings {
+        encodings: config.compression.encodings.clone(),
+        brotli_level: config.compression.brotli_level as i32,
+        zstd_level: config.compression.zstd_level as i32,
+        gzip_level: config.compression.gzip_level as i32,
+        min_size: config.compression.min_size as usize,
+        variant_memory_bytes: config.compression.cache_mb as usize * 1024 * 1024,
+    }));
+
+    let stale_memory_bytes: usize = config.stale.memory_mb as usize * 1024 * 1024;
+    let stale: Option<Arc<stale::StaleStore>> = (stale_memory_bytes > 0 || config.stale.dir.is_some()).then(|| {
+        Arc::new(stale::StaleStore::new(
+            stale_memory_bytes,
+            config.stale.dir.clone(),
+            config.cache.max_object_mb as usize * 1024 * 1024,
+        ))
+    });
+
+    let fallback_page: Option<axum::body::Bytes> = match &config.stale.fallback_page {
+        Some(path) => match std::fs::read(path) {
+            Ok(page) => Some(axum::body::Bytes::from(page)),
+            Err(e) => {
+                tracing::error!("stale.fallback_page: could not read '{}': {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // A read timeout rather than a total one by default, so slow but live downloads are not cut off.
+    let mut client_builder: reqwest::ClientBuilder = Client::builder()
+        .connect_timeout(config.upstream.connect_timeout())
+        .read_timeout(std::time::Duration::from_secs(config.upstream.timeout_secs));
+    if config.upstream.total_timeout_secs > 0 {
+        client_builder = client_builder.timeout(std::time::Duration::from_secs(config.upstream.total_timeout_secs));
+    }
+    let client: Client = match client_builder.build() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("Could not build HTTP client: {}", e);
             std::process::exit(1);
         }
-        Err(_) => {
-            eprintln!("Error: BASE_URL environment variable is not set (use 'www' or 'root')");
+    };
+
+    let listeners: Vec<(std::net::SocketAddr, tokio::net::TcpListener)> = match server::bind(&config.server.bind) {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            tracing::error!("Could not listen on {}", e);
             std::process::exit(1);
         }
     };
+    let admin_listeners: Vec<(std::net::SocketAddr, tokio::net::TcpListener)> = match server::bind(&config.admin.bind) {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            tracing::error!("Could not listen on {}", e);
+            std::process::exit(1);
+        }
+    };
+    let tls_listeners: Vec<(std::net::SocketAddr, tokio::net::TcpListener)> = match server::bind(&config.tls.bind) {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            tracing::error!("Could not listen on {}", e);
+            std::process::exit(1);
+        }
+    };
+    let grace: std::time::Duration = std::time::Duration::from_secs(config.server.shutdown_grace_secs);
+    let limits: server::Limits = server::Limits {
+        max_concurrent_streams: config.server.max_concurrent_streams as u32,
+        idle_timeout: std::time::Duration::from_secs(config.server.idle_timeout_secs),
+    };
+
+    let cert_store: Arc<tls::CertStore> = Arc::new(tls::CertStore::default());
+    let cert_files: Vec<tls::FileCert> = config
+        .tls
+        .cert
+        .iter()
+        .zip(&config.tls.key)
+        .map(|(cert, key): (&std::path::PathBuf, &std::path::PathBuf)| tls::FileCert {
+            hosts: Vec::new(),
+            cert: cert.clone(),
+            key: key.clone(),
+        })
+        .chain(config.tls.certs.iter().map(|cert: &config::HostCert| tls::FileCert {
+            hosts: cert.hosts.clone(),
+            cert: cert.cert.clone(),
+            key: cert.key.clone(),
+        }))
+        .collect();
+    if let Err(e) = tls::load_files(&cert_store, &cert_files) {
+        tracing::error!("Could not load certificate {}", e);
+        std::process::exit(1);
+    }
+    if !cert_files.is_empty() {
+        tls::spawn_reload(cert_store.clone(), cert_files, std::time::Duration::from_secs(config.tls.reload_secs));
+    }
+
+    let tls_config: Arc<rustls::ServerConfig> = tls::server_config(cert_store.clone(), config.tls.http2);
+    let quic: Vec<(std::net::SocketAddr, quinn::Endpoint)> = if config.tls.http3 {
+        match http3::bind(&config.tls.bind, &tls_config, limits) {
+            Ok(endpoints) => endpoints,
+            Err(e) => {
+                tracing::error!("Could not listen on {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    let acme: Option<Arc<acme::Acme>> = match &config.tls.acme.directory {
+        Some(directory) => {
+            let settings: acme::Settings = acme::Settings {
+                directory: directory.clone(),
+                email: config.tls.acme.email.clone(),
+                hosts: config.acme_hosts(),
+                dir: config.tls.acme.dir.clone().expect("validated tls.acme.dir"),
+                ca_file: config.tls.acme.ca_file.clone(),
+                challenge: config.tls.acme.challenge,
+                renew_before: std::time::Duration::from_secs(config.tls.acme.renew_days * 24 * 60 * 60),
+            };
+            match acme::Acme::new(settings, cert_store.clone()) {
+                Ok(acme) => Some(Arc::new(acme)),
+                Err(e) => {
+                    tracing::error!("tls.acme: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let metrics: Arc<metrics::Metrics> = Arc::new(metrics::Metrics::new());
+
+    let mut origins: Vec<String> = config
+        .default_site()
+        .into_iter()
+        .chain(config.tenants())
+        .map(|site: config::SiteSpec| site.staging_url.trim_end_matches('/').to_string())
+        .collect();
+    origins.sort();
+    origins.dedup();
+    let health: Arc<health::Health> = Arc::new(health::Health::new(origins, &config.health.probe_path));
+    health.clone().spawn_probes(
+        client.clone(),
+        std::time::Duration::from_secs(config.health.probe_interval_secs),
+        std::time::Duration::from_secs(config.health.probe_timeout_secs),
+    );
+    let health_routes: Router = health::router(health, &config.health.liveness_path, &config.health.readiness_path);
+    let request_id: axum::http::HeaderName =
+        axum::http::HeaderName::try_from(config.request_id.header.as_str()).expect("validated request_id.header");
 
     let state = AppState {
-        client: Client::new(),
-        webflow_url,
-        prod_url,
-        redirect_mode,
+        client,
+        cache,
+        stale,
+        fallback_page,
+        sites,
+        forwarding: forwarded::Forwarding {
+            trusted: config.proxy.trusted_proxies.clone(),
+            force_https: config.proxy.force_https,
+            emit: config.proxy.forward_headers,
+        },
+        metrics: metrics.clone(),
+        request_id: request_id.clone(),
+        upstream_policy: upstream::Policy {
+            first_byte_timeout: config.upstream.first_byte_timeout(),
+            retries: config.upstream.retries as u32,
+            backoff: std::time::Duration::from_millis(config.upstream.retry_backoff_ms),
+            backoff_max: std::time::Duration::from_millis(config.upstream.retry_backoff_max_ms),
+        },
+        breakers: Arc::new(upstream::Breakers::new(
+            config.upstream.circuit_failures,
+            std::time::Duration::from_secs(config.upstream.circuit_open_secs),
+        )),
     };
 
-    let app: Router = Router::new()
+    // The health and ACME routes are merged after the layers, so probes and challenges skip
+    // redirects, logging and metrics.
+    let mut app: Router = Router::new()
         .route("/*path", any(proxy_handler))
         .fallback(proxy_handler)
-        .layer(CorsLayer::permissive())
-        .with_state(Arc::new(state));
-
-    let listener: tokio::net::TcpListener = tokio::net::TcpListener::bind("0.0.0.0:3000")
-        .await
-        .unwrap();
-    
-    println!("Proxy server running on http://0.0.0.0:3000");
-    axum::serve(listener, app).await.unwrap();
+        .layer(axum::middleware::from_fn_with_state(compression, compression::compress))
+        .layer(axum::middleware::from_fn_with_state(metrics.clone(), metrics::track))
+        .layer(axum::middleware::from_fn_with_state(request_id, request_id::assign))
+        .layer(axum::middleware::from_fn_with_state(config.log.access, logging::access_log))
+        .layer(axum::middleware::from_fn(telemetry::trace))
+        .with_state(Arc::new(state))
+        .merge(health_routes.clone());
+    if let Some(acme) = acme {
+        if acme.challenge() == acme::Challenge::Http01 {
+            app = app.merge(acme::router(acme.clone()));
+        }
+        acme.spawn();
+    }
+
+    let mut services: Vec<server::Service> = vec![server::Service {
+        name: "Proxy server",
+        app: app.clone(),
+        listeners,
+        tls: None,
+        quic: Vec::new(),
+        alt_svc_max_age: std::time::Duration::ZERO,
+    }];
+    if !tls_listeners.is_empty() {
+        services.push(server::Service {
+            name: "Proxy server",
+            app,
+            listeners: tls_listeners,
+            tls: Some(tls_config),
+            quic,
+            alt_svc_max_age: std::time::Duration::from_secs(config.tls.alt_svc_max_age_secs),
+        });
+    }
+    if !admin_listeners.is_empty() {
+        services.push(server::Service {
+            name: "Admin server",
+            app: admin::router(metrics).merge(health_routes),
+            listeners: admin_listeners,
+            tls: None,
+            quic: Vec::new(),
+            alt_svc_max_age: std::time::Duration::ZERO,
+        });
+    }
+
+    server::serve(services, limits, grace).await;
+
+    if let Some(provider) = tracer_provider {
+        telemetry::shutdown(provider).await;
+    }
 }
 
-fn check_redirect(host: &str, uri: &Uri, state: &AppState) -> Option<Redirect> {
-    let host_without_port = host.split(':').next().unwrap_or(host);
-    let is_www = host_without_port.starts_with("www.");
+struct Args {
+    config: Option<std::path::PathBuf>,
+    check_config: bool,
+}
 
-    let path = uri.path();
-    let query = uri.query().map(|q| format!("?{}", q)).unwrap_or_default();
+/// `--config <path>` (or `CONFIG_FILE`) names the config file; `--check-config`
+/// validates it and exits.
+fn parse_args() -> Args {
+    let mut args: Args = Args {
+        config: std::env::var("CONFIG_FILE").ok().filter(|path: &String| !path.is_empty()).map(std::path::PathBuf::from),
+        check_config: false,
+    };
 
-    match (&state.redirect_mode, is_www) {
-        (RedirectMode::Www, false) => {
-            let new_host = format!("www.{}", host_without_port);
-            let redirect_url = format!("https://{}{}{}", new_host, path, query);
-            println!("Redirecting to www: {}", redirect_url);
-            Some(Redirect::permanent(&redirect_url))
-        }
-        (RedirectMode::Root, true) => {
-            let new_host = host_without_port.strip_prefix("www.").unwrap_or(host_without_port);
-            let redirect_url = format!("https://{}{}{}", new_host, path, query);
-            println!("Redirecting to root: {}", redirect_url);
-            Some(Redirect::permanent(&redirect_url))
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--check-config" => args.check_config = true,
+            "--config" => match argv.next() {
+                Some(path) => args.config = Some(std::path::PathBuf::from(path)),
+                None => {
+                    eprintln!("Error: --config needs a path");
+                    std::process::exit(1);
+                }
+            },
+            other => match other.strip_prefix("--config=") {
+                Some(path) => args.config = Some(std::path::PathBuf::from(path)),
+                None => {
+                    eprintln!("Error: unknown argument '{}' (expected --config <path> or --check-config)", other);
+                    std::process::exit(1);
+                }
+            },
         }
+    }
+
+    args
+}
+
+/// The HTTPS upgrade, URL normalization, redirect rules and www/root canonicalization,
+/// resolved into a single hop. Rules are matched against the normalized path, and a rule's
+/// relative target goes straight to the final scheme and host when those change too.
+#[tracing::instrument(target = "otel", skip_all)]
+fn check_redirect(
+    origin: &forwarded::ClientOrigin,
+    uri: &Uri,
+    site: &sites::Site,
+    force_https: bool,
+    metrics: &metrics::Metrics,
+) -> Option<Response> {
+    let is_www = origin.host.starts_with("www.");
+    let upgrade: bool = force_https && origin.scheme == "http";
+
+    let canonical_host: Option<String> = match (&site.redirect_mode, is_www) {
+        (RedirectMode::Www, false) => Some(format!("www.{}", origin.host)),
+        (RedirectMode::Root, true) => Some(origin.host.strip_prefix("www.").unwrap_or(&origin.host).to_string()),
         _ => None,
+    };
+
+    // The client's port only carries over when the scheme stays the same.
+    let new_origin: Option<String> = (upgrade || canonical_host.is_some()).then(|| {
+        let target = forwarded::ClientOrigin {
+            scheme: if upgrade { "https".to_string() } else { origin.scheme.clone() },
+            host: canonical_host.clone().unwrap_or_else(|| origin.host.clone()),
+            port: if upgrade { None } else { origin.port },
+            client_ip: origin.client_ip,
+        };
+        format!("{}://{}", target.scheme, target.authority())
+    });
+
+    let path: std::borrow::Cow<str> = site.normalize.apply(uri.path());
+
+    if let Some(target) = site.redirects.find(&path, uri.query()) {
+        let location: String = match &new_origin {
+            Some(new_origin) if target.location.starts_with('/') => format!("{}{}", new_origin, target.location),
+            _ => target.location,
+        };
+        tracing::debug!("Redirecting {} to {} ({})", uri, location, target.status.as_u16());
+        metrics.redirect("rule");
+        return Some(redirect_response(target.status, &location));
     }
+
+    let normalized: bool = matches!(path, std::borrow::Cow::Owned(_));
+    if new_origin.is_none() && !normalized {
+        return None;
+    }
+
+    let query = uri.query().map(|q| format!("?{}", q)).unwrap_or_default();
+    let redirect_url = format!("{}{}{}", new_origin.unwrap_or_default(), path, query);
+    let reason: &str = match (canonical_host.is_some(), is_www, upgrade) {
+        (true, true, _) => {
+            tracing::debug!("Redirecting to root: {}", redirect_url);
+            "canonical"
+        }
+        (true, false, _) => {
+            tracing::debug!("Redirecting to www: {}", redirect_url);
+            "canonical"
+        }
+        (false, _, true) => {
+            tracing::debug!("Upgrading to https: {}", redirect_url);
+            "https"
+        }
+        (false, _, false) => {
+            tracing::debug!("Normalizing {} to {}", uri.path(), redirect_url);
+            "normalize"
+        }
+    };
+    metrics.redirect(reason);
+    Some(Redirect::permanent(&redirect_url).into_response())
 }
 
+fn redirect_response(status: StatusCode, location: &str) -> Response {
+    match axum::http::HeaderValue::try_from(location) {
+        Ok(location) => (status, [(axum::http::header::LOCATION, location)]).into_response(),
+        Err(_) => {
+            tracing::error!("Redirect target is not a valid header value: {}", location);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(target = "otel", skip_all, fields(server.address = tracing::field::Empty, http.route = tracing::field::Empty))]
 async fn proxy_handler(
     State(state): State<Arc<AppState>>,
-    Host(host): Host,
+    ConnectInfo(peer): ConnectInfo<std::net::SocketAddr>,
+    tls: Option<Extension<server::Tls>>,
+    log: Option<Extension<Arc<logging::RequestLog>>>,
+    tracked: Option<Extension<Arc<metrics::RequestMetrics>>>,
+    uri: Uri,
+    method: axum::http::Method,
+    version: axum::http::Version,
+    mut headers: HeaderMap,
+    body: Body,
+) -> Result<Response, StatusCode> {
+    let scheme: &str = if tls.is_some() { "https" } else { "http" };
+    let host: String = forwarded::request_host(&headers, &uri);
+    let origin: forwarded::ClientOrigin = forwarded::ClientOrigin::from_request(peer, scheme, &host, &headers, &state.forwarding);
+    forwarded::set_upstream_headers(&mut headers, peer, scheme, &host, &origin, &state.forwarding);
+    let host: String = origin.authority();
+    tracing::Span::current().record("server.address", origin.host.as_str());
+    if let Some(Extension(log)) = &log {
+        log.client(origin.client_ip, &host);
+    }
+
+    let Some(site) = state.sites.resolve(&origin.host) else {
+        tracing::warn!("No site configured for host {}", host);
+        return Err(StatusCode::MISDIRECTED_REQUEST);
+    };
+
+    let path: String = uri.path().to_string();
+    let route: Arc<routes::Route> = site.routes.resolve(&path);
+    let preflight: Option<Response> = route.cors.as_ref().and_then(|cors: &cors::CorsPolicy| cors.preflight(&method, &headers));
+    let mut response: Response = match preflight {
+        Some(preflight) => preflight,
+        None => {
+            let request_origin: Option<axum::http::HeaderValue> = headers.get(axum::http::header::ORIGIN).cloned();
+            let mut response: Response = match proxy_site(state, &site, route.clone(), &origin, host, log, tracked, uri, method, version, headers, body).await {
+                Ok(response) => response,
+                Err(status) => status.into_response(),
+            };
+            if let Some(cors) = &route.cors {
+                cors.apply(request_origin.as_ref(), response.headers_mut());
+            }
+            response
+        }
+    };
+    site.security.apply(&path, origin.scheme == "https", response.headers_mut());
+    Ok(response)
+}
+
+/// Everything after the site and route are known: redirects, then the cache and the upstream.
+#[allow(clippy::too_many_arguments)]
+async fn proxy_site(
+    state: Arc<AppState>,
+    site: &sites::Site,
+    route: Arc<routes::Route>,
+    origin: &forwarded::ClientOrigin,
+    host: String,
+    log: Option<Extension<Arc<logging::RequestLog>>>,
+    tracked: Option<Extension<Arc<metrics::RequestMetrics>>>,
     uri: Uri,
     method: axum::http::Method,
+    version: axum::http::Version,
     headers: HeaderMap,
     body: Body,
 ) -> Result<Response, StatusCode> {
+    if let Some(redirect) = check_redirect(origin, &uri, site, state.forwarding.force_https, &state.metrics) {
+        return Ok(redirect);
+    }
 
-    if let Some(redirect) = check_redirect(&host, &uri, &state) {
-        return Ok(redirect.into_response());
+    let target_url: String = route.target_url(&uri);
+    if let Some(Extension(tracked)) = &tracked {
+        tracked.route(&route);
     }
+    tracing::Span::current().record("http.route", route.label());
 
-    let path: &str = uri.path();
-    let query: String = uri.query().map(|q: &str| format!("?{}", q)).unwrap_or_default();
-    let target_url: String = format!("{}{}{}", state.webflow_url, path, query);
+    tracing::debug!("Proxying {} {} -> {}", method, uri, target_url);
 
-    println!("Proxying {} {} -> {}", method, uri, target_url);
+    let page_key: String = cache::Cache::primary_key(&axum::http::Method::GET, &host, &uri);
 
-    let body_bytes: axum::body::Bytes = match body.collect().await {
-        Ok(collected) => collected.to_bytes(),
-        Err(_) => return Err(StatusCode::BAD_REQUEST),
+    let cache: Arc<cache::Cache> = match state.cache.as_ref() {
+        Some(cache) if cache::Cache::is_cacheable_request(&method, &headers) => cache.clone(),
+        _ => {
+            let upstream_body: Option<reqwest::Body> = has_request_body(&method, &headers)
+                .then(|| reqwest::Body::wrap_stream(body.into_data_stream()));
+            let cache_status: Option<&str> = state.cache.as_ref().map(|_| "BYPASS");
+
+            let started: std::time::Instant = std::time::Instant::now();
+            let result = send_upstream(&state, &route, &method, version, &target_url, &headers, upstream_body).await;
+            if let Some(Extension(log)) = &log {
+                log.upstream(started.elapsed());
+            }
+
+            return match result {
+                Ok(response) if !response.status().is_server_error() => {
+                    Ok(respond_upstream(&state, &route, &method, &page_key, &headers, response, None, cache_status))
+                }
+                Ok(response) => upstream_failure(&state, &route, &method, &page_key, &headers, Some(response), cache_status).await,
+                Err(e) => {
+                    tracing::warn!("Proxy error: {}", e);
+                    upstream_failure(&state, &route, &method, &page_key, &headers, None, cache_status).await
+                }
+            };
+        }
     };
 
-    let mut req_builder: reqwest::RequestBuilder = state.client.request(method.clone(), &target_url);
+    let primary: String = cache::Cache::primary_key(&method, &host, &uri);
 
-    for (name, value) in headers.iter() {
-        let name_str: String = name.as_str().to_lowercase();
-        if !matches!(
-            name_str.as_str(),
-            "host" | "connection" | "transfer-encoding" | "content-length"
-        ) {
-            req_builder = req_builder.header(name, value);
+    // The cache wants full responses, so the client's own validators are answered
+    // from the cached entry rather than forwarded.
+    let mut upstream_headers: HeaderMap = headers.clone();
+    upstream_headers.remove("if-none-match");
+    upstream_headers.remove("if-modified-since");
+
+    let cached: Option<Arc<cache::CachedResponse>> = cache.lookup(&primary, &headers).await;
+    if let Some(entry) = &cached {
+        if entry.is_fresh() {
+            return Ok(respond_cached(&route, entry, &headers, "HIT"));
         }
+
+        if entry.within_stale_while_revalidate() {
+            if cache.begin_revalidation(&entry.key) {
+                tokio::spawn(revalidate(
+                    state.clone(),
+                    route.clone(),
+                    cache.clone(),
+                    primary,
+                    method.clone(),
+                    version,
+                    target_url,
+                    upstream_headers,
+                    headers.clone(),
+                    entry.clone(),
+                ));
+            }
+            return Ok(respond_cached(&route, entry, &headers, "STALE"));
+        }
+
+        entry.add_validators(&mut upstream_headers);
     }
 
-    if !body_bytes.is_empty() {
-        req_builder = req_builder.body(body_bytes);
+    let started: std::time::Instant = std::time::Instant::now();
+    let result = send_upstream(&state, &route, &method, version, &target_url, &upstream_headers, None).await;
+    if let Some(Extension(log)) = &log {
+        log.upstream(started.elapsed());
     }
 
-    let response: reqwest::Response = match req_builder.send().await {
-        Ok(resp) => resp,
-        Err(e) => {
-            eprintln!("Proxy error: {}", e);
-            return Err(StatusCode::BAD_GATEWAY);
+    match (result, cached) {
+        (Ok(response), Some(entry)) if response.status() == StatusCode::NOT_MODIFIED => {
+            let refreshed: Arc<cache::CachedResponse> = cache.refresh(&primary, &entry, response.headers()).await;
+            Ok(respond_cached(&route, &refreshed, &headers, "REVALIDATED"))
         }
-    };
+        (Ok(response), Some(entry)) if response.status().is_server_error() && entry.within_stale_if_error() => {
+            tracing::warn!("Upstream returned {} for {}, serving stale copy", response.status(), target_url);
+            Ok(respond_cached(&route, &entry, &headers, "STALE"))
+        }
+        (Err(e), Some(entry)) if entry.within_stale_if_error() => {
+            tracing::warn!("Proxy error: {}, serving stale copy", e);
+            Ok(respond_cached(&route, &entry, &headers, "STALE"))
+        }
+        (Ok(response), _) if response.status().is_server_error() => {
+            upstream_failure(&state, &route, &method, &page_key, &headers, Some(response), Some("MISS")).await
+        }
+        (Ok(response), _) => {
+            let tee: Option<(Arc<cache::Cache>, String)> = Some((cache, primary));
+            Ok(respond_upstream(&state, &route, &method, &page_key, &headers, response, tee, Some("MISS")))
+        }
+        (Err(e), _) => {
+            tracing::warn!("Proxy error: {}", e);
+            upstream_failure(&state, &route, &method, &page_key, &headers, None, Some("MISS")).await
+        }
+    }
+}
 
-    let mut resp_builder: axum::http::response::Builder = Response::builder().status(response.status());
+/// Streams a live upstream response to the client, recording it in the cache (under the
+/// given primary key) and in the stale store on the way through when they apply.
+#[allow(clippy::too_many_arguments)]
+fn respond_upstream(
+    state: &AppState,
+    route: &routes::Route,
+    method: &axum::http::Method,
+    page_key: &str,
+    req_headers: &HeaderMap,
+    response: reqwest::Response,
+    cache: Option<(Arc<cache::Cache>, String)>,
+    cache_status: Option<&str>,
+) -> Response {
+    let status: StatusCode = response.status();
+    let mut resp_headers: HeaderMap = response.headers().clone();
+    resp_headers.append(axum::http::header::VIA, hop_by_hop::via(response.version()));
+    let metrics: Arc<metrics::Metrics> = state.metrics.clone();
+    let upstream: String = route.upstream.clone();
+    let mut stream: BoxStream<'static, Result<axum::body::Bytes, reqwest::Error>> = response
+        .bytes_stream()
+        .inspect_err(move |e: &reqwest::Error| metrics.upstream_error(&upstream, upstream::error_kind(e)))
+        .boxed();
 
-    for (name, value) in response.headers().iter() {
-        let name_str: String = name.as_str().to_lowercase();
-        if !matches!(
-            name_str.as_str(),
-            "transfer-encoding" | "content-length" | "connection" | "content-encoding"
-        ) {
-            resp_builder = resp_builder.header(name, value);
+    if let Some((cache, primary)) = cache {
+        stream = cache.tee(stream, primary, req_headers.clone(), status, resp_headers.clone()).boxed();
+    }
+
+    if let Some(stale) = state.stale.as_ref() {
+        if stale::StaleStore::should_keep(method, req_headers, status, &resp_headers) {
+            stream = stale.clone().keep(stream, page_key.to_string(), &resp_headers).boxed();
         }
     }
 
-    let body_bytes: axum::body::Bytes = match response.bytes().await {
-        Ok(bytes) => bytes,
-        Err(_) => return Err(StatusCode::BAD_GATEWAY),
+    respond(route, status, &resp_headers, stream, cache_status)
+}
+
+/// Webflow failed outright or answered with a 5xx. Page requests get the last good copy,
+/// then the fallback page; everything else gets the upstream error or a bare 502.
+async fn upstream_failure(
+    state: &AppState,
+    route: &routes::Route,
+    method: &axum::http::Method,
+    page_key: &str,
+    req_headers: &HeaderMap,
+    response: Option<reqwest::Response>,
+    cache_status: Option<&str>,
+) -> Result<Response, StatusCode> {
+    if matches!(*method, axum::http::Method::GET | axum::http::Method::HEAD) {
+        if let Some(stale) = state.stale.as_ref() {
+            if let Some(entry) = stale.get(page_key).await {
+                tracing::warn!("Upstream unavailable, serving last good copy of {}", page_key);
+                return Ok(respond_cached(route, &entry, req_headers, "STALE"));
+            }
+        }
+
+        let accepts_html: bool = req_headers
+            .get("accept")
+            .and_then(|v: &axum::http::HeaderValue| v.to_str().ok())
+            .is_some_and(|v: &str| v.contains("text/html"));
+
+        if let (Some(page), true) = (state.fallback_page.as_ref(), accepts_html) {
+            tracing::warn!("Upstream unavailable, serving fallback page for {}", page_key);
+            let mut headers: HeaderMap = HeaderMap::new();
+            headers.insert("content-type", axum::http::HeaderValue::from_static("text/html; charset=utf-8"));
+            headers.insert("cache-control", axum::http::HeaderValue::from_static("no-store"));
+            headers.insert("retry-after", axum::http::HeaderValue::from_static("30"));
+            let request_id: &str = req_headers
+                .get(&state.request_id)
+                .and_then(|v: &axum::http::HeaderValue| v.to_str().ok())
+                .unwrap_or_default();
+            let page: axum::body::Bytes = match std::str::from_utf8(page) {
+                Ok(html) if html.contains("{{request_id}}") => html.replace("{{request_id}}", request_id).into(),
+                _ => page.clone(),
+            };
+            let body = futures_util::stream::iter([Ok::<_, std::io::Error>(page)]);
+            return Ok(respond(route, StatusCode::SERVICE_UNAVAILABLE, &headers, body, cache_status));
+        }
+    }
+
+    match response {
+        Some(response) => Ok(respond_upstream(state, route, method, page_key, req_headers, response, None, cache_status)),
+        None => Err(StatusCode::BAD_GATEWAY),
+    }
+}
+
+#[tracing::instrument(
+    target = "otel",
+    name = "upstream",
+    skip_all,
+    fields(
+        otel.kind = "client",
+        otel.status_code = tracing::field::Empty,
+        http.request.method = %method,
+        url.full = target_url,
+        http.response.status_code = tracing::field::Empty,
+    )
+)]
+async fn send_upstream(
+    state: &AppState,
+    route: &routes::Route,
+    method: &axum::http::Method,
+    version: axum::http::Version,
+    target_url: &str,
+    headers: &HeaderMap,
+    body: Option<reqwest::Body>,
+) -> Result<reqwest::Response, upstream::UpstreamError> {
+    let breaker: Arc<upstream::CircuitBreaker> = state.breakers.get(&route.upstream);
+    let trace_headers: HeaderMap = telemetry::upstream_headers();
+    let hop_by_hop: hop_by_hop::HopByHop = hop_by_hop::HopByHop::of(headers);
+    let may_retry: bool = upstream::Policy::may_retry(method, body.is_some());
+    let mut body: Option<reqwest::Body> = body;
+    let mut attempt: u32 = 0;
+
+    let result: Result<reqwest::Response, upstream::UpstreamError> = loop {
+        if !breaker.allow() {
+            state.metrics.upstream_error(&route.upstream, upstream::UpstreamError::CircuitOpen.kind());
+            break Err(upstream::UpstreamError::CircuitOpen);
+        }
+
+        let mut req_builder: reqwest::RequestBuilder = state.client.request(method.clone(), target_url);
+
+        // reqwest asks for the encodings it can decode itself; the client's choice is applied
+        // when compressing the response.
+        for (name, value) in headers.iter() {
+            if name != axum::http::header::HOST
+                && name != axum::http::header::ACCEPT_ENCODING
+                && !hop_by_hop.contains(name)
+                && !route.headers.replaces_request(name)
+                && !trace_headers.contains_key(name)
+            {
+                req_builder = req_builder.header(name, value);
+            }
+        }
+        req_builder = req_builder.header(axum::http::header::VIA, hop_by_hop::via(version));
+
+        for (name, value) in &trace_headers {
+            req_builder = req_builder.header(name, value);
+        }
+
+        for (name, value) in &route.headers.request_set {
+            req_builder = req_builder.header(name, value);
+        }
+
+        if let Some(body) = body.take() {
+            req_builder = req_builder.body(body);
+        }
+
+        let timeout: std::time::Duration = state.upstream_policy.first_byte_timeout;
+        let result: Result<reqwest::Response, upstream::UpstreamError> = match tokio::time::timeout(timeout, req_builder.send()).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) => Err(upstream::UpstreamError::Request(e)),
+            Err(_) => Err(upstream::UpstreamError::FirstByteTimeout(timeout)),
+        };
+
+        match &result {
+            Ok(response) => breaker.record(!response.status().is_server_error()),
+            Err(e) => {
+                breaker.record(false);
+                state.metrics.upstream_error(&route.upstream, e.kind());
+            }
+        }
+
+        if !may_retry || !state.upstream_policy.should_retry(attempt, &result) {
+            break result;
+        }
+        attempt += 1;
+        let delay: std::time::Duration = state.upstream_policy.delay(attempt);
+        match &result {
+            Ok(response) => tracing::debug!("Retrying {} {} in {}ms after {}", method, target_url, delay.as_millis(), response.status()),
+            Err(e) => tracing::debug!("Retrying {} {} in {}ms after: {}", method, target_url, delay.as_millis(), e),
+        }
+        tokio::time::sleep(delay).await;
     };
 
-    let content_type: &str = resp_builder
-        .headers_ref()
-        .and_then(|h: &HeaderMap| h.get("content-type"))
-        .and_then(|v: &axum::http::HeaderValue| v.to_str().ok())
-        .unwrap_or("");
+    let span: tracing::Span = tracing::Span::current();
+    match &result {
+        Ok(response) => {
+            span.record("http.response.status_code", response.status().as_u16());
+            if response.status().is_server_error() {
+                span.record("otel.status_code", "ERROR");
+            }
+        }
+        Err(_) => {
+            span.record("otel.status_code", "ERROR");
+        }
+    }
+    result
+}
 
-    let modified_body: Vec<u8> = if content_type.contains("text/html") {
-        let html: std::borrow::Cow<'_, str> = String::from_utf8_lossy(&body_bytes);
+/// Builds the client response from upstream (or cached) headers and body, applying the
+/// header filter and whichever body rewriter matches the content type.
+fn respond<S, E>(route: &routes::Route, status: StatusCode, headers: &HeaderMap, stream: S, cache_status: Option<&str>) -> Response
+where
+    S: futures_util::Stream<Item = Result<axum::body::Bytes, E>> + Send + Unpin + 'static,
+    E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+{
+    let mut resp_builder: axum::http::response::Builder = Response::builder().status(status);
+    let hop_by_hop: hop_by_hop::HopByHop = hop_by_hop::HopByHop::of(headers);
 
-        let wf_domain_re: Regex = Regex::new(r#"data-wf-domain="[^"]*""#).unwrap();
-        let modified: std::borrow::Cow<'_, str> = wf_domain_re.replace_all(&html, format!(r#"data-wf-domain="{}""#, state.prod_url));
+    for (name, value) in headers.iter() {
+        if name != axum::http::header::CONTENT_LENGTH
+            && name != axum::http::header::CONTENT_ENCODING
+            && !hop_by_hop.contains(name)
+            && !route.headers.replaces_response(name)
+        {
+            resp_builder = resp_builder.header(name, value);
+        }
+    }
 
-        modified.into_owned().into_bytes()
-    } else {
-        body_bytes.to_vec()
+    for (name, value) in &route.headers.response_set {
+        resp_builder = resp_builder.header(name, value);
+    }
+
+    if let Some(cache_status) = cache_status {
+        resp_builder = resp_builder.header("x-cache", cache_status);
+    }
+
+    let content_type: String = headers
+        .get("content-type")
+        .and_then(|v: &axum::http::HeaderValue| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+
+    let body: Body = match origin::ContentKind::from_content_type(&content_type) {
+        Some(origin::ContentKind::Html) => route.html_rewriter.rewrite(stream, &content_type),
+        Some(kind) if route.html_rewriter.origin.applies_to(kind) => route.html_rewriter.origin.rewrite(stream),
+        _ => Body::from_stream(stream),
     };
 
-    Ok(resp_builder
-        .body(Body::from(modified_body))
+    resp_builder
+        .body(body)
         .unwrap()
-        .into_response())
-}
\ No newline at end of file
+        .into_response()
+}
+
+fn respond_cached(route: &routes::Route, entry: &cache::CachedResponse, req_headers: &HeaderMap, cache_status: &str) -> Response {
+    let mut headers: HeaderMap = entry.header_map();
+    headers.insert("age", axum::http::HeaderValue::from(entry.age()));
+    if cache_status == "STALE" {
+        headers.insert("warning", axum::http::HeaderValue::from_static("110 - \"Response is Stale\""));
+    }
+
+    if entry.matches_validators(req_headers) {
+        headers.remove("content-type");
+        let empty = futures_util::stream::empty::<Result<axum::body::Bytes, std::io::Error>>();
+        return respond(route, StatusCode::NOT_MODIFIED, &headers, empty, Some(cache_status));
+    }
+
+    let body = futures_util::stream::iter([Ok::<_, std::io::Error>(entry.body.clone())]);
+    let mut response: Response = respond(route, entry.status(), &headers, body, Some(cache_status));
+    response.extensions_mut().insert(compression::StoredBody {
+        key: entry.key.clone(),
+        body_id: entry.body_id(),
+    });
+    response
+}
+
+/// Refreshes a stale entry in the background while the stale copy is being served.
+#[allow(clippy::too_many_arguments)]
+async fn revalidate(
+    state: Arc<AppState>,
+    route: Arc<routes::Route>,
+    cache: Arc<cache::Cache>,
+    primary: String,
+    method: axum::http::Method,
+    version: axum::http::Version,
+    target_url: String,
+    mut upstream_headers: HeaderMap,
+    req_headers: HeaderMap,
+    entry: Arc<cache::CachedResponse>,
+) {
+    entry.add_validators(&mut upstream_headers);
+
+    match send_upstream(&state, &route, &method, version, &target_url, &upstream_headers, None).await {
+        Ok(response) if response.status() == StatusCode::NOT_MODIFIED => {
+            cache.refresh(&primary, &entry, response.headers()).await;
+        }
+        Ok(response) if !response.status().is_server_error() => {
+            let status: StatusCode = response.status();
+            let mut resp_headers: HeaderMap = response.headers().clone();
+            resp_headers.append(axum::http::header::VIA, hop_by_hop::via(response.version()));
+            match response.bytes().await {
+                Ok(body) => cache.store(&primary, &req_headers, status, &resp_headers, body).await,
+                Err(e) => tracing::warn!("Revalidation of {} failed: {}", target_url, e),
+            }
+        }
+        Ok(response) => tracing::warn!("Revalidation of {} returned {}", target_url, response.status()),
+        Err(e) => tracing::warn!("Revalidation of {} failed: {}", target_url, e),
+    }
+
+    cache.end_revalidation(&entry.key);
+}
+
+/// Only attach a body to the upstream request when the client actually sent one,
+/// so GET/HEAD requests are not turned into chunked uploads.
+fn has_request_body(method: &axum::http::Method, headers: &HeaderMap) -> bool {
+    if headers.contains_key("transfer-encoding") {
+        return true;
+    }
+
+    match headers
+        .get("content-length")
+        .and_then(|v: &axum::http::HeaderValue| v.to_str().ok())
+        .and_then(|v: &str| v.parse::<u64>().ok())
+    {
+        Some(len) => len > 0,
+        None => !matches!(*method, axum::http::Method::GET | axum::http::Method::HEAD | axum::http::Method::OPTIONS),
+    }
+}
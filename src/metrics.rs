@@ -0,0 +1,205 @@
+//! Prometheus metrics, served at `/metrics` on the admin listener.
+//!
+//! Requests are counted once their response body has been sent (or the client went away),
+//! so latency and bytes cover the whole transfer, like the access log. The route and
+//! upstream labels are filled in by the proxy handler; requests that never reach a route,
+//! such as redirects, are labelled `none`. The cache hit ratio is
+//! `proxy_cache_requests_total{result="HIT"}` over the sum across results.
+
+use crate::routes::Route;
+use axum::body::{Body, Bytes};
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    upstream_errors: IntCounterVec,
+    cache: IntCounterVec,
+    redirects: IntCounterVec,
+    bytes_in: IntCounter,
+    bytes_out: IntCounter,
+    in_flight: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry: Registry = Registry::new();
+        let labels: [&str; 3] = ["route", "status", "upstream"];
+
+        let metrics: Metrics = Metrics {
+            requests: IntCounterVec::new(Opts::new("proxy_requests_total", "Requests handled, by route, status and upstream."), &labels)
+                .expect("valid metric"),
+            duration: HistogramVec::new(
+                HistogramOpts::new("proxy_request_duration_seconds", "Time from request to the end of the response body."),
+                &labels,
+            )
+            .expect("valid metric"),
+            upstream_errors: IntCounterVec::new(
+                Opts::new("proxy_upstream_errors_total", "Failed upstream requests, by upstream and error kind."),
+                &["upstream", "kind"],
+            )
+            .expect("valid metric"),
+            cache: IntCounterVec::new(Opts::new("proxy_cache_requests_total", "Responses by cache result (X-Cache)."), &["result"])
+                .expect("valid metric"),
+            redirects: IntCounterVec::new(Opts::new("proxy_redirects_total", "Redirects issued, by reason."), &["reason"])
+                .expect("valid metric"),
+            bytes_in: IntCounter::new("proxy_request_bytes_total", "Request body bytes received from clients.").expect("valid metric"),
+            bytes_out: IntCounter::new("proxy_response_bytes_total", "Response body bytes sent to clients.").expect("valid metric"),
+            in_flight: IntGauge::new("proxy_requests_in_flight", "Requests currently being handled.").expect("valid metric"),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 8] = [
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.duration.clone()),
+            Box::new(metrics.upstream_errors.clone()),
+            Box::new(metrics.cache.clone()),
+            Box::new(metrics.redirects.clone()),
+            Box::new(metrics.bytes_in.clone()),
+            Box::new(metrics.bytes_out.clone()),
+            Box::new(metrics.in_flight.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).expect("metric names are unique");
+        }
+
+        metrics
+    }
+
+    /// `reason` is `https`, `canonical`, `normalize` or `rule`.
+    pub fn redirect(&self, reason: &str) {
+        self.redirects.with_label_values(&[reason]).inc();
+    }
+
+    /// `kind` is one of `upstream::UpstreamError::kind`.
+    pub fn upstream_error(&self, upstream: &str, kind: &str) {
+        self.upstream_errors.with_label_values(&[upstream, kind]).inc();
+    }
+
+    /// The text exposition format.
+    pub fn render(&self) -> String {
+        let mut buffer: Vec<u8> = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!("Could not encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// The route a request was sent to, set by the proxy handler once it is known.
+#[derive(Default)]
+pub struct RequestMetrics {
+    route: Mutex<Option<(String, String)>>,
+}
+
+impl RequestMetrics {
+    pub fn route(&self, route: &Route) {
+        *self.route.lock().unwrap() = Some((route.label().to_string(), route.upstream.clone()));
+    }
+}
+
+/// Middleware that tracks in-flight requests, body bytes both ways and, once the response
+/// body is done, the request count and latency.
+pub async fn track(State(metrics): State<Arc<Metrics>>, mut request: Request, next: Next) -> Response {
+    let started: Instant = Instant::now();
+    metrics.in_flight.inc();
+
+    let details: Arc<RequestMetrics> = Arc::new(RequestMetrics::default());
+    request.extensions_mut().insert(details.clone());
+
+    let request: Request = request.map(|body: Body| {
+        Body::new(CountedBody {
+            inner: body,
+            bytes: metrics.bytes_in.clone(),
+            finish: None,
+        })
+    });
+
+    let response: Response = next.run(request).await;
+    if let Some(result) = response.headers().get("x-cache").and_then(|v| v.to_str().ok()) {
+        metrics.cache.with_label_values(&[result]).inc();
+    }
+
+    let finish: Finish = Finish {
+        status: response.status().as_u16(),
+        bytes: metrics.bytes_out.clone(),
+        metrics,
+        details,
+        started,
+    };
+    response.map(|body: Body| {
+        Body::new(CountedBody {
+            inner: body,
+            bytes: finish.bytes.clone(),
+            finish: Some(finish),
+        })
+    })
+}
+
+struct Finish {
+    metrics: Arc<Metrics>,
+    details: Arc<RequestMetrics>,
+    started: Instant,
+    status: u16,
+    bytes: IntCounter,
+}
+
+impl Finish {
+    fn record(&self) {
+        let route = self.details.route.lock().unwrap();
+        let (route, upstream): (&str, &str) = route.as_ref().map(|(r, u)| (r.as_str(), u.as_str())).unwrap_or(("none", "none"));
+        let status: String = self.status.to_string();
+        let labels: [&str; 3] = [route, &status, upstream];
+
+        self.metrics.requests.with_label_values(&labels).inc();
+        self.metrics.duration.with_label_values(&labels).observe(self.started.elapsed().as_secs_f64());
+        self.metrics.in_flight.dec();
+    }
+}
+
+/// Adds the size of every data frame to a counter, and records the request on drop when
+/// it is the response body.
+struct CountedBody {
+    inner: Body,
+    bytes: IntCounter,
+    finish: Option<Finish>,
+}
+
+impl http_body::Body for CountedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<http_body::Frame<Bytes>, axum::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.bytes.inc_by(data.len() as u64);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CountedBody {
+    fn drop(&mut self) {
+        if let Some(finish) = self.finish.take() {
+            finish.record();
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! URL normalization policies applied in the redirect stage.
+//!
+//! Every policy runs on the path together, so `//About/index.html` becomes `/about/` (or
+//! `/about`) in one redirect instead of a chain. Each policy has its own exclusions, matched
+//! as globs against the path as requested.
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use std::borrow::Cow;
+
+#[derive(Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrailingSlashMode {
+    #[default]
+    Keep,
+    Add,
+    Strip,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawTrailingSlash {
+    mode: TrailingSlashMode,
+    exclude: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawToggle {
+    enabled: bool,
+    exclude: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawNormalize {
+    trailing_slash: RawTrailingSlash,
+    lowercase: RawToggle,
+    collapse_slashes: RawToggle,
+    strip_index: RawToggle,
+}
+
+/// A policy that is off, or on everywhere except the excluded paths.
+#[derive(Clone, Default)]
+struct Policy {
+    enabled: bool,
+    exclude: GlobSet,
+}
+
+impl Policy {
+    fn new(key: &str, enabled: bool, exclude: &[String]) -> Result<Policy, String> {
+        let mut builder: GlobSetBuilder = GlobSetBuilder::new();
+        for pattern in exclude {
+            let glob: Glob = globset::GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|e: globset::Error| format!("normalize.{}.exclude: invalid glob '{}': {}", key, pattern, e))?;
+            builder.add(glob);
+        }
+        let exclude: GlobSet = builder.build().map_err(|e: globset::Error| format!("normalize.{}.exclude: {}", key, e))?;
+        Ok(Policy { enabled, exclude })
+    }
+
+    fn applies(&self, path: &str) -> bool {
+        self.enabled && !self.exclude.is_match(path)
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(try_from = "RawNormalize")]
+pub struct NormalizePolicy {
+    trailing_slash: TrailingSlashMode,
+    trailing_slash_policy: Policy,
+    lowercase: Policy,
+    collapse_slashes: Policy,
+    strip_index: Policy,
+}
+
+impl TryFrom<RawNormalize> for NormalizePolicy {
+    type Error = String;
+
+    fn try_from(raw: RawNormalize) -> Result<NormalizePolicy, String> {
+        Ok(NormalizePolicy {
+            trailing_slash: raw.trailing_slash.mode,
+            trailing_slash_policy: Policy::new(
+                "trailing_slash",
+                raw.trailing_slash.mode != TrailingSlashMode::Keep,
+                &raw.trailing_slash.exclude,
+            )?,
+            lowercase: Policy::new("lowercase", raw.lowercase.enabled, &raw.lowercase.exclude)?,
+            collapse_slashes: Policy::new("collapse_slashes", raw.collapse_slashes.enabled, &raw.collapse_slashes.exclude)?,
+            strip_index: Policy::new("strip_index", raw.strip_index.enabled, &raw.strip_index.exclude)?,
+        })
+    }
+}
+
+impl NormalizePolicy {
+    /// The normalized form of `path`, borrowed when nothing changes.
+    pub fn apply<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let mut out: String = path.to_string();
+
+        if self.collapse_slashes.applies(path) && out.contains("//") {
+            let mut collapsed: String = String::with_capacity(out.len());
+            for c in out.chars() {
+                if !(c == '/' && collapsed.ends_with('/')) {
+                    collapsed.push(c);
+                }
+            }
+            out = collapsed;
+        }
+
+        if self.strip_index.applies(path) {
+            if let Some(dir) = out.strip_suffix("index.html").or_else(|| out.strip_suffix("index.htm")) {
+                if dir.ends_with('/') {
+                    out = dir.to_string();
+                }
+            }
+        }
+
+        if self.lowercase.applies(path) && out.chars().any(|c: char| c.is_ascii_uppercase()) {
+            out = out.to_ascii_lowercase();
+        }
+
+        if self.trailing_slash_policy.applies(path) && out != "/" {
+            match self.trailing_slash {
+                // Paths that look like files (`/style.css`) never get a slash added.
+                TrailingSlashMode::Add if !out.ends_with('/') && !last_segment(&out).contains('.') => out.push('/'),
+                TrailingSlashMode::Strip => {
+                    let trimmed: &str = out.trim_end_matches('/');
+                    out = if trimmed.is_empty() { "/".to_string() } else { trimmed.to_string() };
+                }
+                _ => {}
+            }
+        }
+
+        if out == path {
+            Cow::Borrowed(path)
+        } else {
+            Cow::Owned(out)
+        }
+    }
+}
+
+fn last_segment(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
@@ -0,0 +1,208 @@
+//! Rewrites absolute references to the Webflow staging host so they point at production.
+//!
+//! HTML goes through the [`crate::html`] rewriter, which calls [`OriginRewrite::replace`]
+//! on attributes and text nodes. CSS, JavaScript, XML and plain text are rewritten here
+//! as raw bytes, carrying a small tail between chunks so a match split across two
+//! network reads is still found.
+
+use aho_corasick::{AhoCorasick, MatchKind};
+use axum::body::{Body, Bytes};
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// The response families that can have staging URLs rewritten.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentKind {
+    Html,
+    Css,
+    Js,
+    Xml,
+    Text,
+}
+
+impl ContentKind {
+    pub fn from_content_type(content_type: &str) -> Option<ContentKind> {
+        let mime: String = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+
+        match mime.as_str() {
+            "text/html" | "application/xhtml+xml" => Some(ContentKind::Html),
+            "text/css" => Some(ContentKind::Css),
+            "text/javascript" | "application/javascript" | "application/x-javascript" | "application/json"
+            | "application/ld+json" | "application/manifest+json" => Some(ContentKind::Js),
+            "text/xml" | "application/xml" | "application/rss+xml" | "application/atom+xml" => Some(ContentKind::Xml),
+            "text/plain" => Some(ContentKind::Text),
+            _ => None,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<ContentKind> {
+        match name {
+            "html" => Some(ContentKind::Html),
+            "css" => Some(ContentKind::Css),
+            "js" => Some(ContentKind::Js),
+            "xml" => Some(ContentKind::Xml),
+            "text" => Some(ContentKind::Text),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a comma separated list such as `html,css,xml`. `none` disables every kind.
+pub fn parse_content_kinds(value: &str) -> Result<Vec<ContentKind>, String> {
+    let value: &str = value.trim();
+    if value.eq_ignore_ascii_case("none") || value.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    value
+        .split(',')
+        .map(|name: &str| {
+            let name: String = name.trim().to_ascii_lowercase();
+            ContentKind::from_name(&name)
+                .ok_or_else(|| format!("unknown content type '{}' (use html, css, js, xml or text)", name))
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct OriginRewrite {
+    matcher: Arc<AhoCorasick>,
+    replacements: Arc<Vec<String>>,
+    longest: usize,
+    kinds: Vec<ContentKind>,
+}
+
+impl OriginRewrite {
+    /// `staging_url` is the Webflow origin (`https://site.webflow.io`) and `prod_host` the
+    /// public host links should use. Plain, protocol-relative and JSON-escaped
+    /// (`https:\/\/`) forms are all matched. `extra` adds literal `(from, to)` pairs.
+    pub fn new(staging_url: &str, prod_host: &str, kinds: Vec<ContentKind>, extra: &[(String, String)]) -> OriginRewrite {
+        let staging_host: &str = staging_url
+            .split("://")
+            .last()
+            .unwrap_or(staging_url)
+            .trim_end_matches('/');
+
+        let mut patterns: Vec<String> = vec![format!("//{}", staging_host), format!("\\/\\/{}", staging_host)];
+        let mut replacements: Vec<String> = vec![format!("//{}", prod_host), format!("\\/\\/{}", prod_host)];
+        for (from, to) in extra {
+            patterns.push(from.clone());
+            replacements.push(to.clone());
+        }
+        let longest: usize = patterns.iter().map(String::len).max().unwrap_or(0);
+
+        let matcher: AhoCorasick = AhoCorasick::builder()
+            .match_kind(MatchKind::LeftmostLongest)
+            .ascii_case_insensitive(true)
+            .build(&patterns)
+            .expect("rewrite patterns are plain literals");
+
+        OriginRewrite {
+            matcher: Arc::new(matcher),
+            replacements: Arc::new(replacements),
+            longest,
+            kinds,
+        }
+    }
+
+    pub fn applies_to(&self, kind: ContentKind) -> bool {
+        self.kinds.contains(&kind)
+    }
+
+    pub fn replace<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.matcher.is_match(text) {
+            Cow::Owned(self.matcher.replace_all(text, &self.replacements))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    /// Rewrites a non-HTML body as it streams. All patterns are ASCII, so this is safe
+    /// for any ASCII-compatible charset without decoding.
+    pub fn rewrite<S, E>(&self, upstream: S) -> Body
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + Unpin + 'static,
+        E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+    {
+        let state = StreamState {
+            upstream,
+            rewrite: self.clone(),
+            carry: Vec::new(),
+            done: false,
+        };
+
+        Body::from_stream(futures_util::stream::unfold(state, next_chunk))
+    }
+
+    /// Replaces every complete match in `buf`, returning the rewritten bytes that are safe
+    /// to emit and leaving in `buf` the tail that could still be the start of a match.
+    fn replace_prefix(&self, buf: &mut Vec<u8>, last: bool) -> Vec<u8> {
+        let safe_end: usize = if last {
+            buf.len()
+        } else {
+            buf.len().saturating_sub(self.longest - 1)
+        };
+
+        let mut out: Vec<u8> = Vec::with_capacity(buf.len());
+        let mut pos: usize = 0;
+        for m in self.matcher.find_iter(buf.as_slice()) {
+            if m.start() >= safe_end {
+                break;
+            }
+            out.extend_from_slice(&buf[pos..m.start()]);
+            out.extend_from_slice(self.replacements[m.pattern().as_usize()].as_bytes());
+            pos = m.end();
+        }
+
+        let emit_to: usize = safe_end.max(pos);
+        out.extend_from_slice(&buf[pos..emit_to]);
+        buf.drain(..emit_to);
+        out
+    }
+}
+
+struct StreamState<S> {
+    upstream: S,
+    rewrite: OriginRewrite,
+    carry: Vec<u8>,
+    done: bool,
+}
+
+async fn next_chunk<S, E>(mut state: StreamState<S>) -> Option<(Result<Bytes, std::io::Error>, StreamState<S>)>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    if state.done {
+        return None;
+    }
+
+    loop {
+        match state.upstream.next().await {
+            Some(Ok(chunk)) => {
+                state.carry.extend_from_slice(&chunk);
+                let out: Vec<u8> = state.rewrite.replace_prefix(&mut state.carry, false);
+                if !out.is_empty() {
+                    return Some((Ok(Bytes::from(out)), state));
+                }
+            }
+            Some(Err(e)) => {
+                state.done = true;
+                return Some((Err(std::io::Error::other(e)), state));
+            }
+            None => {
+                state.done = true;
+                let out: Vec<u8> = state.rewrite.replace_prefix(&mut state.carry, true);
+                return Some((Ok(Bytes::from(out)), state));
+            }
+        }
+    }
+}
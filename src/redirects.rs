@@ -0,0 +1,345 @@
+//! Redirect rules for legacy URLs, checked before a request is proxied.
+//!
+//! Exact rules are a hash lookup and prefix rules one lookup per path segment, so tens of
+//! thousands of imported URLs cost the same as a handful. Regex rules go through a single
+//! `RegexSet`. An exact match beats the longest matching prefix, which beats the first
+//! matching regex.
+
+use axum::http::StatusCode;
+use regex::{Regex, RegexSet};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum MatchKind {
+    Exact,
+    Prefix,
+    Regex,
+}
+
+/// What happens to the incoming query string.
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum QueryMode {
+    /// Appended to the target, after any query the target already has.
+    Preserve,
+    /// Only the target's own query is kept.
+    Drop,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    from: String,
+    to: String,
+    #[serde(default = "default_status")]
+    status: u16,
+    #[serde(rename = "match", default = "default_match")]
+    kind: MatchKind,
+    #[serde(default = "default_query")]
+    query: QueryMode,
+}
+
+fn default_status() -> u16 {
+    301
+}
+
+fn default_match() -> MatchKind {
+    MatchKind::Exact
+}
+
+fn default_query() -> QueryMode {
+    QueryMode::Preserve
+}
+
+struct Rule {
+    to: String,
+    status: StatusCode,
+    query: QueryMode,
+}
+
+/// A matched rule, ready to become a `Location` header.
+pub struct RedirectTarget {
+    pub status: StatusCode,
+    pub location: String,
+}
+
+/// Rules from `rules` come before rules from `file`, which is a CSV of
+/// `from,to[,status[,match[,query]]]` with an optional header row.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawRedirects {
+    file: Option<PathBuf>,
+    rules: Vec<RuleConfig>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(try_from = "RawRedirects")]
+pub struct RedirectTable {
+    inner: Arc<Rules>,
+}
+
+#[derive(Default)]
+struct Rules {
+    rules: Vec<Rule>,
+    exact: HashMap<String, usize>,
+    prefix: HashMap<String, usize>,
+    regex_set: Option<RegexSet>,
+    regexes: Vec<(Regex, usize)>,
+}
+
+impl TryFrom<RawRedirects> for RedirectTable {
+    type Error = String;
+
+    fn try_from(raw: RawRedirects) -> Result<RedirectTable, String> {
+        let mut rules: Vec<(String, RuleConfig)> = raw
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, rule): (usize, RuleConfig)| (format!("redirects.rules[{}]", i), rule))
+            .collect();
+
+        if let Some(path) = &raw.file {
+            rules.extend(read_csv(path)?);
+        }
+
+        RedirectTable::new(rules)
+    }
+}
+
+impl RedirectTable {
+    /// Compiles rules labelled with where they came from, for error messages.
+    fn new(configs: Vec<(String, RuleConfig)>) -> Result<RedirectTable, String> {
+        let mut table: Rules = Rules::default();
+        let mut patterns: Vec<String> = Vec::new();
+
+        for (source, config) in configs {
+            let status: StatusCode = match config.status {
+                301 | 302 | 307 | 308 => StatusCode::from_u16(config.status).expect("known redirect status"),
+                other => return Err(format!("{}: status must be 301, 302, 307 or 308, got {}", source, other)),
+            };
+            if config.to.is_empty() {
+                return Err(format!("{}: 'to' must not be empty", source));
+            }
+
+            let index: usize = table.rules.len();
+            match config.kind {
+                MatchKind::Exact | MatchKind::Prefix if !config.from.starts_with('/') => {
+                    return Err(format!("{}: 'from' must start with '/', got '{}'", source, config.from));
+                }
+                // The first rule for a path wins, so file rules cannot shadow inline ones.
+                MatchKind::Exact => {
+                    table.exact.entry(config.from).or_insert(index);
+                }
+                MatchKind::Prefix => {
+                    table.prefix.entry(config.from.trim_end_matches('/').to_string()).or_insert(index);
+                }
+                MatchKind::Regex => {
+                    let regex: Regex = Regex::new(&config.from).map_err(|e| format!("{}: invalid regex '{}': {}", source, config.from, e))?;
+                    patterns.push(config.from);
+                    table.regexes.push((regex, index));
+                }
+            }
+
+            table.rules.push(Rule {
+                to: config.to,
+                status,
+                query: config.query,
+            });
+        }
+
+        if !patterns.is_empty() {
+            table.regex_set = Some(RegexSet::new(&patterns).map_err(|e| format!("redirects: {}", e))?);
+        }
+
+        Ok(RedirectTable { inner: Arc::new(table) })
+    }
+
+    pub fn find(&self, path: &str, query: Option<&str>) -> Option<RedirectTarget> {
+        let table: &Rules = &self.inner;
+
+        let (rule, location): (&Rule, String) = if let Some(&index) = table.exact.get(path) {
+            (&table.rules[index], table.rules[index].to.clone())
+        } else if let Some((index, rest)) = table.find_prefix(path) {
+            let rule: &Rule = &table.rules[index];
+            let location: String = format!("{}{}", rule.to.trim_end_matches('/'), rest);
+            // `to = "/"` with nothing left of the path would otherwise be an empty Location.
+            (rule, if location.is_empty() { "/".to_string() } else { location })
+        } else {
+            let set: &RegexSet = table.regex_set.as_ref()?;
+            let first: usize = set.matches(path).iter().next()?;
+            let (regex, index) = &table.regexes[first];
+            let captures: regex::Captures = regex.captures(path)?;
+            let mut location: String = String::new();
+            captures.expand(&table.rules[*index].to, &mut location);
+            (&table.rules[*index], location)
+        };
+
+        let location: String = match (rule.query, query) {
+            (QueryMode::Preserve, Some(query)) if !query.is_empty() => {
+                let separator: char = if location.contains('?') { '&' } else { '?' };
+                format!("{}{}{}", location, separator, query)
+            }
+            _ => location,
+        };
+
+        Some(RedirectTarget {
+            status: rule.status,
+            location,
+        })
+    }
+}
+
+impl Rules {
+    /// The longest prefix rule covering `path` on a segment boundary, and the rest of the path.
+    fn find_prefix<'a>(&self, path: &'a str) -> Option<(usize, &'a str)> {
+        if self.prefix.is_empty() {
+            return None;
+        }
+
+        let mut end: usize = path.len();
+        loop {
+            let candidate: &str = path[..end].trim_end_matches('/');
+            if let Some(&index) = self.prefix.get(candidate) {
+                return Some((index, &path[candidate.len()..]));
+            }
+            end = candidate.rfind('/')?;
+        }
+    }
+}
+
+fn read_csv(path: &Path) -> Result<Vec<(String, RuleConfig)>, String> {
+    let mut reader: csv::Reader<std::fs::File> = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .from_path(path)
+        .map_err(|e: csv::Error| format!("redirects.file: could not read '{}': {}", path.display(), e))?;
+
+    let mut rules: Vec<(String, RuleConfig)> = Vec::new();
+    for record in reader.records() {
+        let record: csv::StringRecord = record.map_err(|e: csv::Error| format!("{}: {}", path.display(), e))?;
+        let line: u64 = record.position().map(|p: &csv::Position| p.line()).unwrap_or(0);
+        let source: String = format!("{} line {}", path.display(), line);
+        let field = |i: usize| record.get(i).filter(|value: &&str| !value.is_empty());
+
+        if line == 1 && field(0) == Some("from") {
+            continue;
+        }
+
+        let (Some(from), Some(to)) = (field(0), field(1)) else {
+            return Err(format!("{}: expected at least 'from,to'", source));
+        };
+
+        let status: u16 = match field(2) {
+            Some(value) => value.parse().map_err(|_| format!("{}: status must be a number, got '{}'", source, value))?,
+            None => default_status(),
+        };
+        let kind: MatchKind = match field(3) {
+            Some("exact") | None => MatchKind::Exact,
+            Some("prefix") => MatchKind::Prefix,
+            Some("regex") => MatchKind::Regex,
+            Some(other) => return Err(format!("{}: match must be exact, prefix or regex, got '{}'", source, other)),
+        };
+        let query: QueryMode = match field(4) {
+            Some("preserve") | None => QueryMode::Preserve,
+            Some("drop") => QueryMode::Drop,
+            Some(other) => return Err(format!("{}: query must be preserve or drop, got '{}'", source, other)),
+        };
+
+        rules.push((
+            source,
+            RuleConfig {
+                from: from.to_string(),
+                to: to.to_string(),
+                status,
+                kind,
+                query,
+            },
+        ));
+    }
+
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(rules: &[(&str, &str, MatchKind)]) -> RedirectTable {
+        let configs: Vec<(String, RuleConfig)> = rules
+            .iter()
+            .enumerate()
+            .map(|(i, (from, to, kind))| {
+                let config: RuleConfig = RuleConfig {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                    status: default_status(),
+                    kind: *kind,
+                    query: default_query(),
+                };
+                (format!("rules[{}]", i), config)
+            })
+            .collect();
+        RedirectTable::new(configs).unwrap()
+    }
+
+    fn location(table: &RedirectTable, path: &str, query: Option<&str>) -> Option<String> {
+        table.find(path, query).map(|target: RedirectTarget| target.location)
+    }
+
+    #[test]
+    fn exact_rules_match_the_whole_path() {
+        let table: RedirectTable = table(&[("/old", "/new", MatchKind::Exact)]);
+        assert_eq!(location(&table, "/old", None).as_deref(), Some("/new"));
+        assert_eq!(location(&table, "/old/page", None), None);
+        assert_eq!(location(&table, "/old", Some("a=1")).as_deref(), Some("/new?a=1"));
+    }
+
+    #[test]
+    fn prefix_rules_carry_the_rest_of_the_path() {
+        let table: RedirectTable = table(&[("/blog", "/news/", MatchKind::Prefix)]);
+        assert_eq!(location(&table, "/blog", None).as_deref(), Some("/news"));
+        assert_eq!(location(&table, "/blog/", None).as_deref(), Some("/news/"));
+        assert_eq!(location(&table, "/blog/2020/post", None).as_deref(), Some("/news/2020/post"));
+        assert_eq!(location(&table, "/blogroll", None), None);
+    }
+
+    #[test]
+    fn prefix_rule_to_the_root_never_gives_an_empty_location() {
+        let table: RedirectTable = table(&[("/shop", "/", MatchKind::Prefix)]);
+        assert_eq!(location(&table, "/shop", None).as_deref(), Some("/"));
+        assert_eq!(location(&table, "/shop/", None).as_deref(), Some("/"));
+        assert_eq!(location(&table, "/shop/item", None).as_deref(), Some("/item"));
+        assert_eq!(location(&table, "/shop", Some("a=1")).as_deref(), Some("/?a=1"));
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let table: RedirectTable = table(&[("/a", "/x", MatchKind::Prefix), ("/a/b", "/y", MatchKind::Prefix)]);
+        assert_eq!(location(&table, "/a/b/c", None).as_deref(), Some("/y/c"));
+        assert_eq!(location(&table, "/a/c", None).as_deref(), Some("/x/c"));
+    }
+
+    #[test]
+    fn regex_rules_expand_captures() {
+        let table: RedirectTable = table(&[(r"^/posts/(\d+)$", "/articles/$1", MatchKind::Regex)]);
+        assert_eq!(location(&table, "/posts/42", None).as_deref(), Some("/articles/42"));
+        assert_eq!(location(&table, "/posts/abc", None), None);
+    }
+
+    #[test]
+    fn exact_beats_prefix_beats_regex() {
+        let table: RedirectTable = table(&[
+            ("^/docs/.*$", "/regex", MatchKind::Regex),
+            ("/docs", "/prefix", MatchKind::Prefix),
+            ("/docs/intro", "/exact", MatchKind::Exact),
+        ]);
+        assert_eq!(location(&table, "/docs/intro", None).as_deref(), Some("/exact"));
+        assert_eq!(location(&table, "/docs/other", None).as_deref(), Some("/prefix/other"));
+    }
+}
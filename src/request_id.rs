@@ -0,0 +1,68 @@
+//! Request IDs for correlating client reports, access log entries and upstream logs.
+//!
+//! A request keeps the ID it arrived with when it looks sane, and gets a new UUID otherwise.
+//! The ID travels to the upstream in the request headers, comes back on the response, and is
+//! attached to every log line through a span with the `request` target.
+
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{HeaderName, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use http_body::Body as _;
+use tracing::Instrument;
+
+/// The target of the per-request span carrying the ID, which the log filter always lets through.
+pub const SPAN_TARGET: &str = "request";
+
+/// The ID of the request being handled, in the request and response extensions.
+#[derive(Clone)]
+pub struct RequestId(pub HeaderValue);
+
+impl RequestId {
+    pub fn as_str(&self) -> &str {
+        self.0.to_str().unwrap_or_default()
+    }
+}
+
+/// Middleware that assigns the ID, echoes it on the response and gives bodyless error
+/// responses a short page naming it.
+pub async fn assign(State(header): State<HeaderName>, mut request: Request, next: Next) -> Response {
+    let id: HeaderValue = match request.headers().get(&header) {
+        Some(value) if is_acceptable(value) => value.clone(),
+        _ => HeaderValue::try_from(uuid::Uuid::new_v4().to_string()).expect("a UUID is a valid header value"),
+    };
+    let request_id: RequestId = RequestId(id.clone());
+
+    request.headers_mut().insert(header.clone(), id.clone());
+    request.extensions_mut().insert(request_id.clone());
+
+    let span: tracing::Span = tracing::info_span!(target: SPAN_TARGET, "request", request_id = request_id.as_str());
+    let mut response: Response = next.run(request).instrument(span).await;
+
+    let status: StatusCode = response.status();
+    if (status.is_client_error() || status.is_server_error()) && response.body().size_hint().exact() == Some(0) {
+        let page: String = format!(
+            "{} {}\nRequest ID: {}\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or_default(),
+            request_id.as_str()
+        );
+        let (mut parts, _) = response.into_parts();
+        parts.headers.insert("content-type", HeaderValue::from_static("text/plain; charset=utf-8"));
+        parts.headers.remove("content-length");
+        response = Response::from_parts(parts, Body::from(page));
+    }
+
+    response.headers_mut().insert(header, id);
+    response.extensions_mut().insert(request_id);
+    response
+}
+
+/// Up to 128 characters that are safe to log and pass on: letters, digits and `-_.:/+=`.
+fn is_acceptable(value: &HeaderValue) -> bool {
+    let bytes: &[u8] = value.as_bytes();
+    !bytes.is_empty()
+        && bytes.len() <= 128
+        && bytes.iter().all(|b: &u8| b.is_ascii_alphanumeric() || b"-_.:/+=".contains(b))
+}
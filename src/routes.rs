@@ -0,0 +1,179 @@
+//! Ordered path routing to upstream origins.
+//!
+//! Routes are tried in configuration order and the first match wins. Anything unmatched
+//! goes to the Webflow site, which is itself a route so the rest of the proxy only ever
+//! deals with a resolved [`Route`].
+
+use crate::config::{HeaderRules, RewriteConfig, RouteConfig, SiteSpec};
+use crate::cors::CorsPolicy;
+use crate::html;
+use crate::origin::OriginRewrite;
+use axum::http::Uri;
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub enum PathMatcher {
+    /// Matches whole path segments, so `/docs` covers `/docs` and `/docs/x` but not `/docsx`.
+    Prefix(String),
+    Glob(globset::GlobMatcher),
+    Regex(regex::Regex),
+}
+
+impl PathMatcher {
+    pub fn prefix(prefix: &str) -> Result<PathMatcher, String> {
+        if !prefix.starts_with('/') {
+            return Err(format!("prefix must start with '/', got '{}'", prefix));
+        }
+        Ok(PathMatcher::Prefix(prefix.trim_end_matches('/').to_string()))
+    }
+
+    pub fn glob(pattern: &str) -> Result<PathMatcher, String> {
+        globset::GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map(|glob: globset::Glob| PathMatcher::Glob(glob.compile_matcher()))
+            .map_err(|e: globset::Error| format!("invalid glob '{}': {}", pattern, e))
+    }
+
+    pub fn regex(pattern: &str) -> Result<PathMatcher, String> {
+        regex::Regex::new(pattern)
+            .map(PathMatcher::Regex)
+            .map_err(|e: regex::Error| format!("invalid regex '{}': {}", pattern, e))
+    }
+
+    /// The pattern as configured, used to label metrics.
+    fn pattern(&self) -> &str {
+        match self {
+            PathMatcher::Prefix(prefix) if prefix.is_empty() => "/",
+            PathMatcher::Prefix(prefix) => prefix,
+            PathMatcher::Glob(glob) => glob.glob().glob(),
+            PathMatcher::Regex(regex) => regex.as_str(),
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            PathMatcher::Prefix(prefix) => match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => prefix.is_empty() || rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            },
+            PathMatcher::Glob(glob) => glob.is_match(path),
+            PathMatcher::Regex(regex) => regex.is_match(path),
+        }
+    }
+}
+
+/// How the matched path is changed before it is sent upstream.
+#[derive(Clone)]
+pub enum PathRewrite {
+    Keep,
+    /// Drops the route prefix, so `/docs/intro` reaches the upstream as `/intro`.
+    StripPrefix,
+    /// Swaps the route prefix for another one.
+    ReplacePrefix(String),
+    /// A regex replacement template such as `/posts/$1`, for regex routes.
+    Regex(String),
+}
+
+pub struct Route {
+    matcher: Option<PathMatcher>,
+    path_rewrite: PathRewrite,
+    pub upstream: String,
+    pub headers: HeaderRules,
+    /// `None` leaves CORS to the upstream.
+    pub cors: Option<CorsPolicy>,
+    pub html_rewriter: html::Rewriter,
+}
+
+impl Route {
+    /// The route's path pattern, or `default` for the Webflow site.
+    pub fn label(&self) -> &str {
+        self.matcher.as_ref().map(PathMatcher::pattern).unwrap_or("default")
+    }
+
+    /// The upstream URL for a request, with the path rewritten and the query kept.
+    pub fn target_url(&self, uri: &Uri) -> String {
+        let path: &str = uri.path();
+        let query: String = uri.query().map(|q: &str| format!("?{}", q)).unwrap_or_default();
+
+        let path: String = match (&self.path_rewrite, &self.matcher) {
+            (PathRewrite::StripPrefix, Some(PathMatcher::Prefix(prefix))) => with_leading_slash(&path[prefix.len()..]),
+            (PathRewrite::ReplacePrefix(to), Some(PathMatcher::Prefix(prefix))) => {
+                format!("{}{}", to.trim_end_matches('/'), with_leading_slash(&path[prefix.len()..]))
+            }
+            (PathRewrite::Regex(template), Some(PathMatcher::Regex(regex))) => regex.replace(path, template.as_str()).into_owned(),
+            _ => path.to_string(),
+        };
+
+        format!("{}{}{}", self.upstream, path, query)
+    }
+}
+
+#[derive(Clone)]
+pub struct RouteTable {
+    routes: Vec<Arc<Route>>,
+    default: Arc<Route>,
+}
+
+impl RouteTable {
+    /// Builds a site's routes from validated config. The site's headers apply to all routes,
+    /// with a route's own settings winning; a route without a `rewrite` or `cors` table
+    /// inherits the site's.
+    pub fn new(site: &SiteSpec, canonical_host: &str) -> RouteTable {
+        let build = |matcher: Option<PathMatcher>,
+                     path_rewrite: PathRewrite,
+                     upstream: &str,
+                     headers: HeaderRules,
+                     rewrite: &RewriteConfig,
+                     cors: Option<&CorsPolicy>| {
+            let upstream: String = upstream.trim_end_matches('/').to_string();
+            let replacements: Vec<(String, String)> = rewrite.replace.iter().map(|r| (r.from.clone(), r.to.clone())).collect();
+            Arc::new(Route {
+                matcher,
+                path_rewrite,
+                html_rewriter: html::Rewriter {
+                    prod_url: site.prod_url.to_string(),
+                    origin: OriginRewrite::new(&upstream, canonical_host, rewrite.content_types.clone(), &replacements),
+                },
+                upstream,
+                headers,
+                cors: cors.filter(|cors: &&CorsPolicy| !cors.passthrough()).cloned(),
+            })
+        };
+
+        let routes: Vec<Arc<Route>> = site
+            .routes
+            .iter()
+            .map(|route: &RouteConfig| {
+                build(
+                    Some(route.matcher.clone()),
+                    route.path_rewrite.clone(),
+                    &route.upstream,
+                    site.headers.merged(&route.headers),
+                    route.rewrite.as_ref().unwrap_or(site.rewrite),
+                    route.cors.as_ref().or(site.cors),
+                )
+            })
+            .collect();
+
+        let default: Arc<Route> = build(None, PathRewrite::Keep, site.staging_url, site.headers.clone(), site.rewrite, site.cors);
+
+        RouteTable { routes, default }
+    }
+
+    pub fn resolve(&self, path: &str) -> Arc<Route> {
+        self.routes
+            .iter()
+            .find(|route: &&Arc<Route>| route.matcher.as_ref().is_some_and(|m: &PathMatcher| m.matches(path)))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+fn with_leading_slash(path: &str) -> String {
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    }
+}
@@ -0,0 +1,301 @@
+//! Security response headers, set per site and per path.
+//!
+//! A policy starts from a preset, has its own settings layered on top, and may give path
+//! rules that change individual headers again; the first rule matching the request path
+//! wins. The headers are applied last, so they replace whatever the upstream or `[headers]`
+//! sent. HSTS only ever goes out over HTTPS.
+
+use crate::routes::PathMatcher;
+use axum::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+
+/// What HSTS may claim before browsers accept a site into their preload lists.
+const PRELOAD_MIN_AGE_SECS: u64 = 31_536_000;
+
+/// Headers to set (`Some`) or remove (`None`).
+type Rules = Vec<(HeaderName, Option<HeaderValue>)>;
+
+/// Everything Webflow pages load by default: jQuery and the webfont loader, site assets on
+/// the Webflow CDNs, Google Fonts, reCAPTCHA on forms, form submissions to webflow.com and
+/// the usual video embeds. Ecommerce and custom code embeds need their own hosts on top.
+const WEBFLOW_CSP: &str = concat!(
+    "default-src 'self'; ",
+    "script-src 'self' 'unsafe-inline' https://d3e54v103j8qbb.cloudfront.net https://ajax.googleapis.com ",
+    "https://cdn.prod.website-files.com https://assets.website-files.com https://assets-global.website-files.com ",
+    "https://www.google.com https://www.gstatic.com; ",
+    "style-src 'self' 'unsafe-inline' https://cdn.prod.website-files.com https://assets.website-files.com ",
+    "https://assets-global.website-files.com https://fonts.googleapis.com; ",
+    "font-src 'self' data: https://cdn.prod.website-files.com https://assets.website-files.com ",
+    "https://assets-global.website-files.com https://fonts.gstatic.com; ",
+    "img-src 'self' data: blob: https:; ",
+    "media-src 'self' blob: https://cdn.prod.website-files.com https://assets.website-files.com https://assets-global.website-files.com; ",
+    "connect-src 'self' https://webflow.com https://*.webflow.com https://cdn.prod.website-files.com ",
+    "https://assets.website-files.com https://assets-global.website-files.com; ",
+    "frame-src 'self' https://www.google.com https://www.youtube.com https://www.youtube-nocookie.com https://player.vimeo.com; ",
+    "form-action 'self' https://webflow.com; ",
+    "object-src 'none'; base-uri 'self'; frame-ancestors 'self'",
+);
+
+#[derive(Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    /// No headers beyond the ones configured.
+    #[default]
+    Off,
+    /// HSTS, nosniff, same-origin framing, a referrer policy, a permissions policy and COOP.
+    Basic,
+    /// `basic` plus a Content-Security-Policy that Webflow's runtime works under.
+    Webflow,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Hsts {
+    /// 0 drops the header.
+    max_age_secs: u64,
+    #[serde(default)]
+    include_subdomains: bool,
+    #[serde(default)]
+    preload: bool,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Csp {
+    /// Empty drops the header.
+    policy: String,
+    /// Sent as `Content-Security-Policy-Report-Only`, so violations are reported, not blocked.
+    #[serde(default)]
+    report_only: bool,
+}
+
+/// One set of header settings; unset ones fall through to the layer below. An empty string
+/// (or `false`) removes the header from the response.
+#[derive(Clone, Default)]
+struct Layer {
+    hsts: Option<Hsts>,
+    csp: Option<Csp>,
+    frame_options: Option<String>,
+    referrer_policy: Option<String>,
+    permissions_policy: Option<String>,
+    nosniff: Option<bool>,
+    cross_origin_opener_policy: Option<String>,
+    cross_origin_embedder_policy: Option<String>,
+}
+
+impl Layer {
+    fn preset(preset: Preset) -> Layer {
+        let basic = || Layer {
+            hsts: Some(Hsts {
+                max_age_secs: PRELOAD_MIN_AGE_SECS,
+                include_subdomains: false,
+                preload: false,
+            }),
+            csp: None,
+            frame_options: Some("SAMEORIGIN".to_string()),
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+            permissions_policy: Some("camera=(), microphone=(), geolocation=()".to_string()),
+            nosniff: Some(true),
+            // Popups keep working, which PayPal and social logins rely on.
+            cross_origin_opener_policy: Some("same-origin-allow-popups".to_string()),
+            // require-corp would block the cross-origin CDN assets and embeds.
+            cross_origin_embedder_policy: None,
+        };
+
+        match preset {
+            Preset::Off => Layer::default(),
+            Preset::Basic => basic(),
+            Preset::Webflow => Layer {
+                csp: Some(Csp {
+                    policy: WEBFLOW_CSP.to_string(),
+                    report_only: false,
+                }),
+                ..basic()
+            },
+        }
+    }
+
+    /// This layer's settings, with `base` filling in the ones it leaves unset.
+    fn over(&self, base: &Layer) -> Layer {
+        Layer {
+            hsts: self.hsts.clone().or_else(|| base.hsts.clone()),
+            csp: self.csp.clone().or_else(|| base.csp.clone()),
+            frame_options: self.frame_options.clone().or_else(|| base.frame_options.clone()),
+            referrer_policy: self.referrer_policy.clone().or_else(|| base.referrer_policy.clone()),
+            permissions_policy: self.permissions_policy.clone().or_else(|| base.permissions_policy.clone()),
+            nosniff: self.nosniff.or(base.nosniff),
+            cross_origin_opener_policy: self.cross_origin_opener_policy.clone().or_else(|| base.cross_origin_opener_policy.clone()),
+            cross_origin_embedder_policy: self.cross_origin_embedder_policy.clone().or_else(|| base.cross_origin_embedder_policy.clone()),
+        }
+    }
+
+    /// The resulting rules, with every value checked.
+    fn rules(&self, key: &str) -> Result<Rules, String> {
+        let mut out: Rules = Vec::new();
+        let value = |field: &str, value: &str| -> Result<Option<HeaderValue>, String> {
+            if value.is_empty() {
+                return Ok(None);
+            }
+            HeaderValue::from_str(value)
+                .map(Some)
+                .map_err(|_| format!("{}.{}: '{}' is not a valid header value", key, field, value))
+        };
+
+        if let Some(hsts) = &self.hsts {
+            if hsts.preload && (!hsts.include_subdomains || hsts.max_age_secs < PRELOAD_MIN_AGE_SECS) {
+                return Err(format!(
+                    "{}.hsts: preload needs include_subdomains and a max_age_secs of at least {}",
+                    key, PRELOAD_MIN_AGE_SECS
+                ));
+            }
+            let mut directives: String = format!("max-age={}", hsts.max_age_secs);
+            if hsts.include_subdomains {
+                directives.push_str("; includeSubDomains");
+            }
+            if hsts.preload {
+                directives.push_str("; preload");
+            }
+            let hsts: Option<HeaderValue> = (hsts.max_age_secs > 0).then(|| HeaderValue::try_from(directives).expect("HSTS directives are ASCII"));
+            out.push((header::STRICT_TRANSPORT_SECURITY, hsts));
+        }
+        if let Some(csp) = &self.csp {
+            let name: HeaderName = if csp.report_only {
+                header::CONTENT_SECURITY_POLICY_REPORT_ONLY
+            } else {
+                header::CONTENT_SECURITY_POLICY
+            };
+            out.push((name, value("csp.policy", &csp.policy)?));
+        }
+        if let Some(frame_options) = &self.frame_options {
+            out.push((header::X_FRAME_OPTIONS, value("frame_options", frame_options)?));
+        }
+        if let Some(referrer_policy) = &self.referrer_policy {
+            out.push((header::REFERRER_POLICY, value("referrer_policy", referrer_policy)?));
+        }
+        if let Some(permissions_policy) = &self.permissions_policy {
+            out.push((HeaderName::from_static("permissions-policy"), value("permissions_policy", permissions_policy)?));
+        }
+        if let Some(nosniff) = self.nosniff {
+            out.push((header::X_CONTENT_TYPE_OPTIONS, nosniff.then(|| HeaderValue::from_static("nosniff"))));
+        }
+        if let Some(coop) = &self.cross_origin_opener_policy {
+            out.push((HeaderName::from_static("cross-origin-opener-policy"), value("cross_origin_opener_policy", coop)?));
+        }
+        if let Some(coep) = &self.cross_origin_embedder_policy {
+            out.push((HeaderName::from_static("cross-origin-embedder-policy"), value("cross_origin_embedder_policy", coep)?));
+        }
+        Ok(out)
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawSecurity {
+    preset: Preset,
+    hsts: Option<Hsts>,
+    csp: Option<Csp>,
+    frame_options: Option<String>,
+    referrer_policy: Option<String>,
+    permissions_policy: Option<String>,
+    nosniff: Option<bool>,
+    cross_origin_opener_policy: Option<String>,
+    cross_origin_embedder_policy: Option<String>,
+    paths: Vec<RawPathRule>,
+}
+
+/// Changes some headers for matching paths. Exactly one of `prefix`, `glob` or `regex`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPathRule {
+    prefix: Option<String>,
+    glob: Option<String>,
+    regex: Option<String>,
+    hsts: Option<Hsts>,
+    csp: Option<Csp>,
+    frame_options: Option<String>,
+    referrer_policy: Option<String>,
+    permissions_policy: Option<String>,
+    nosniff: Option<bool>,
+    cross_origin_opener_policy: Option<String>,
+    cross_origin_embedder_policy: Option<String>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(try_from = "RawSecurity")]
+pub struct SecurityPolicy {
+    rules: Rules,
+    paths: Vec<(PathMatcher, Rules)>,
+}
+
+impl TryFrom<RawSecurity> for SecurityPolicy {
+    type Error = String;
+
+    fn try_from(raw: RawSecurity) -> Result<SecurityPolicy, String> {
+        let layer: Layer = Layer {
+            hsts: raw.hsts,
+            csp: raw.csp,
+            frame_options: raw.frame_options,
+            referrer_policy: raw.referrer_policy,
+            permissions_policy: raw.permissions_policy,
+            nosniff: raw.nosniff,
+            cross_origin_opener_policy: raw.cross_origin_opener_policy,
+            cross_origin_embedder_policy: raw.cross_origin_embedder_policy,
+        }
+        .over(&Layer::preset(raw.preset));
+
+        let mut paths: Vec<(PathMatcher, Rules)> = Vec::new();
+        for (i, rule) in raw.paths.into_iter().enumerate() {
+            let key: String = format!("security.paths[{}]", i);
+            let matcher: PathMatcher = match (&rule.prefix, &rule.glob, &rule.regex) {
+                (Some(prefix), None, None) => PathMatcher::prefix(prefix),
+                (None, Some(glob), None) => PathMatcher::glob(glob),
+                (None, None, Some(regex)) => PathMatcher::regex(regex),
+                _ => Err("needs exactly one of prefix, glob or regex".to_string()),
+            }
+            .map_err(|e: String| format!("{}: {}", key, e))?;
+
+            let rule_layer: Layer = Layer {
+                hsts: rule.hsts,
+                csp: rule.csp,
+                frame_options: rule.frame_options,
+                referrer_policy: rule.referrer_policy,
+                permissions_policy: rule.permissions_policy,
+                nosniff: rule.nosniff,
+                cross_origin_opener_policy: rule.cross_origin_opener_policy,
+                cross_origin_embedder_policy: rule.cross_origin_embedder_policy,
+            };
+            paths.push((matcher, rule_layer.over(&layer).rules(&key)?));
+        }
+
+        Ok(SecurityPolicy {
+            rules: layer.rules("security")?,
+            paths,
+        })
+    }
+}
+
+impl SecurityPolicy {
+    /// Sets and removes this policy's headers for a request to `path`.
+    pub fn apply(&self, path: &str, secure: bool, headers: &mut HeaderMap) {
+        let rules: &[(HeaderName, Option<HeaderValue>)] = self
+            .paths
+            .iter()
+            .find(|(matcher, _)| matcher.matches(path))
+            .map_or(&self.rules, |(_, rules)| rules);
+
+        for (name, value) in rules {
+            // Browsers ignore HSTS over plain HTTP, where an attacker could have added it.
+            if name == header::STRICT_TRANSPORT_SECURITY && !secure {
+                continue;
+            }
+            match value {
+                Some(value) => {
+                    headers.insert(name, value.clone());
+                }
+                None => {
+                    headers.remove(name);
+                }
+            }
+        }
+    }
+}
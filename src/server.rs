@@ -0,0 +1,398 @@
+//! Listeners and graceful shutdown.
+//!
+//! Each configured address gets its own listener serving its service's router. On SIGTERM or
+//! SIGINT the listeners stop accepting, in-flight requests get the grace period to finish,
+//! and whatever is still running after that is dropped.
+//!
+//! Connections are served by hyper directly rather than through `axum::serve`, so TLS and
+//! the connection limits apply the same way on every listener. Plain listeners speak HTTP/1.1
+//! and h2c; TLS listeners pick HTTP/2 or HTTP/1.1 by ALPN. Requests carry the same
+//! `ConnectInfo` either way, plus a [`Tls`] extension when the client connected securely.
+
+use crate::{http3, tls};
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::header::ALT_SVC;
+use axum::http::HeaderValue;
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::task::TaskTracker;
+use tower::ServiceExt;
+
+/// How long a client gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Marks requests that arrived over a TLS listener, HTTP/3 included.
+#[derive(Clone, Copy)]
+pub struct Tls;
+
+/// Per-connection limits, shared by every listener.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    /// Streams one HTTP/2 or HTTP/3 connection may have open at once.
+    pub max_concurrent_streams: u32,
+    /// A connection with no request open and nothing sent either way for this long is closed.
+    /// It also bounds how long an HTTP/1.1 client may take to send the request headers.
+    pub idle_timeout: Duration,
+}
+
+/// Binds every address up front so a bad one fails startup instead of a later listener.
+pub fn bind(addrs: &[SocketAddr]) -> io::Result<Vec<(SocketAddr, TcpListener)>> {
+    addrs
+        .iter()
+        .map(|addr: &SocketAddr| {
+            bind_one(*addr, v6_only(*addr, addrs))
+                .map(|listener: TcpListener| (*addr, listener))
+                .map_err(|e: io::Error| io::Error::new(e.kind(), format!("{}: {}", addr, e)))
+        })
+        .collect()
+}
+
+/// `[::]` is dual-stack by default on Linux, which would collide with an explicit IPv4
+/// listener on the same port.
+pub fn v6_only(addr: SocketAddr, addrs: &[SocketAddr]) -> bool {
+    addr.is_ipv6() && addrs.iter().any(|other: &SocketAddr| other.is_ipv4() && other.port() == addr.port())
+}
+
+fn bind_one(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+    if v6_only {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// A router and the listeners it is served on.
+pub struct Service {
+    /// Used in log messages, e.g. `Proxy server` or `Admin server`.
+    pub name: &'static str,
+    pub app: Router,
+    pub listeners: Vec<(SocketAddr, TcpListener)>,
+    /// Serves HTTPS instead of plain HTTP when set.
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    /// HTTP/3 endpoints, advertised with `Alt-Svc` on the TLS listener of the same port.
+    pub quic: Vec<(SocketAddr, quinn::Endpoint)>,
+    /// The `ma` of the `Alt-Svc` header.
+    pub alt_svc_max_age: Duration,
+}
+
+/// Serves every service until a shutdown signal arrives, then drains them together.
+pub async fn serve(services: Vec<Service>, limits: Limits, grace: Duration) {
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut servers: tokio::task::JoinSet<()> = tokio::task::JoinSet::new();
+
+    for service in services {
+        for (addr, listener) in service.listeners {
+            let acceptor: Option<TlsAcceptor> = service.tls.clone().map(TlsAcceptor::from);
+            let alt_svc: Option<HeaderValue> = service
+                .quic
+                .iter()
+                .find(|(quic, _)| quic.port() == addr.port())
+                .filter(|_| acceptor.is_some())
+                .and_then(|_| HeaderValue::try_from(format!("h3=\":{}\"; ma={}", addr.port(), service.alt_svc_max_age.as_secs())).ok());
+            let scheme: &str = if acceptor.is_some() { "https" } else { "http" };
+            tracing::info!("{} running on {}://{}", service.name, scheme, addr);
+
+            let connections: Connections = Connections {
+                app: service.app.clone(),
+                acceptor,
+                alt_svc,
+                limits,
+            };
+            servers.spawn(accept(listener, connections, shutdown_rx.clone()));
+        }
+        for (addr, endpoint) in service.quic {
+            tracing::info!("{} running on https://{} (HTTP/3)", service.name, addr);
+            servers.spawn(http3::serve(endpoint, service.app.clone(), shutdown_rx.clone()));
+        }
+    }
+
+    tokio::select! {
+        _ = shutdown_signal() => {}
+        // A listener that dies on its own takes the process down with it, as before.
+        _ = servers.join_next() => {}
+    }
+
+    tracing::info!("Shutting down, waiting up to {}s for in-flight requests", grace.as_secs());
+    let _ = shutdown_tx.send(true);
+
+    let drained = async {
+        while servers.join_next().await.is_some() {}
+    };
+    if tokio::time::timeout(grace, drained).await.is_err() {
+        tracing::warn!("Grace period over, dropping remaining connections");
+        servers.abort_all();
+    }
+}
+
+/// How the connections of one listener are served.
+#[derive(Clone)]
+struct Connections {
+    app: Router,
+    acceptor: Option<TlsAcceptor>,
+    alt_svc: Option<HeaderValue>,
+    limits: Limits,
+}
+
+/// Accepts connections until shutdown, then waits for the open ones to finish.
+async fn accept(listener: TcpListener, connections: Connections, mut shutdown_rx: watch::Receiver<bool>) {
+    let tracker: TaskTracker = TaskTracker::new();
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown_rx.wait_for(|stop: &bool| *stop) => break,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Usually out of file descriptors; give connections a moment to close.
+                tracing::debug!("Could not accept a connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let connections: Connections = connections.clone();
+        let shutdown_rx: watch::Receiver<bool> = shutdown_rx.clone();
+        tracker.spawn(async move {
+            let Some(acceptor) = connections.acceptor.clone() else {
+                connections.serve(stream, peer, shutdown_rx).await;
+                return;
+            };
+
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    tracing::debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    tracing::debug!("TLS handshake with {} timed out", peer);
+                    return;
+                }
+            };
+            // A TLS-ALPN-01 validation only looks at the certificate.
+            if stream.get_ref().1.alpn_protocol() == Some(tls::ACME_TLS_ALPN) {
+                return;
+            }
+            connections.serve(stream, peer, shutdown_rx).await;
+        });
+    }
+
+    tracker.close();
+    tracker.wait().await;
+}
+
+impl Connections {
+    async fn serve<S>(self, stream: S, peer: SocketAddr, mut shutdown_rx: watch::Receiver<bool>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut builder: auto::Builder<TokioExecutor> = auto::Builder::new(TokioExecutor::new());
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(self.limits.idle_timeout);
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .max_concurrent_streams(self.limits.max_concurrent_streams);
+
+        let activity: Arc<Activity> = Arc::new(Activity::new());
+        let secure: bool = self.acceptor.is_some();
+        let (app, alt_svc) = (self.app, self.alt_svc);
+        let requests: Arc<Activity> = activity.clone();
+        let service = hyper::service::service_fn(move |request: hyper::Request<hyper::body::Incoming>| {
+            let mut request: axum::extract::Request = request.map(Body::new);
+            request.extensions_mut().insert(ConnectInfo(peer));
+            if secure {
+                request.extensions_mut().insert(Tls);
+            }
+            let app: Router = app.clone();
+            let alt_svc: Option<HeaderValue> = alt_svc.clone();
+            let open: OpenRequest = OpenRequest::start(requests.clone());
+            async move {
+                let mut response: axum::response::Response = app.oneshot(request).await?;
+                if let Some(alt_svc) = alt_svc {
+                    response.headers_mut().entry(ALT_SVC).or_insert(alt_svc);
+                }
+                drop(open);
+                Ok::<axum::response::Response, std::convert::Infallible>(response)
+            }
+        });
+
+        let io = TokioIo::new(Tracked {
+            inner: stream,
+            activity: activity.clone(),
+        });
+        let connection = builder.serve_connection_with_upgrades(io, service);
+        tokio::pin!(connection);
+
+        let mut closing: bool = false;
+        loop {
+            tokio::select! {
+                result = connection.as_mut() => {
+                    if let Err(e) = result {
+                        tracing::debug!("Connection from {} ended: {}", peer, e);
+                    }
+                    break;
+                }
+                // Lets open requests finish, then closes; HTTP/2 clients are sent a GOAWAY.
+                _ = shutdown_rx.wait_for(|stop: &bool| *stop), if !closing => {
+                    closing = true;
+                    connection.as_mut().graceful_shutdown();
+                }
+                _ = activity.idle_for(self.limits.idle_timeout), if !closing => {
+                    closing = true;
+                    connection.as_mut().graceful_shutdown();
+                }
+            }
+        }
+    }
+}
+
+/// When a connection last moved any bytes, and how many of its requests await a response.
+struct Activity {
+    started: Instant,
+    last_millis: AtomicU64,
+    open_requests: AtomicUsize,
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity {
+            started: Instant::now(),
+            last_millis: AtomicU64::new(0),
+            open_requests: AtomicUsize::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        self.last_millis.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Resolves once the connection has been idle for `timeout`.
+    async fn idle_for(&self, timeout: Duration) {
+        loop {
+            let deadline: Instant = self.started + Duration::from_millis(self.last_millis.load(Ordering::Relaxed)) + timeout;
+            let now: Instant = Instant::now();
+            if now >= deadline && self.open_requests.load(Ordering::Relaxed) == 0 {
+                return;
+            }
+            // A request still waiting on its response is looked at again a second later.
+            tokio::time::sleep_until(deadline.max(now + Duration::from_secs(1)).into()).await;
+        }
+    }
+}
+
+/// Counts a request as open until its response head is ready. Streaming the body counts as
+/// activity through the connection's writes.
+struct OpenRequest(Arc<Activity>);
+
+impl OpenRequest {
+    fn start(activity: Arc<Activity>) -> OpenRequest {
+        activity.open_requests.fetch_add(1, Ordering::Relaxed);
+        OpenRequest(activity)
+    }
+}
+
+impl Drop for OpenRequest {
+    fn drop(&mut self) {
+        self.0.open_requests.fetch_sub(1, Ordering::Relaxed);
+        self.0.touch();
+    }
+}
+
+/// A connection's stream, noting every read and write in its [`Activity`].
+struct Tracked<S> {
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before: usize = buf.filled().len();
+        let result: Poll<io::Result<()>> = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > before {
+            self.activity.touch();
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result: Poll<io::Result<usize>> = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if matches!(result, Poll::Ready(Ok(written)) if written > 0) {
+            self.activity.touch();
+        }
+        result
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        let result: Poll<io::Result<usize>> = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if matches!(result, Poll::Ready(Ok(written)) if written > 0) {
+            self.activity.touch();
+        }
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Could not listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Could not listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
@@ -0,0 +1,121 @@
+//! Host-based site mapping, so one process can front many Webflow sites.
+//!
+//! Each site answers on its production domain with and without `www.`, and carries its own
+//! redirect mode, redirect rules, URL normalization, security headers and route table.
+//! Hosts no site claims fall through to the `[site]` table.
+
+use crate::config::{self, Config, RedirectMode, SiteSpec};
+use crate::forwarded;
+use crate::normalize::NormalizePolicy;
+use crate::redirects::RedirectTable;
+use crate::routes::RouteTable;
+use crate::security::SecurityPolicy;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub struct Site {
+    pub redirect_mode: RedirectMode,
+    pub routes: RouteTable,
+    pub redirects: RedirectTable,
+    pub normalize: NormalizePolicy,
+    pub security: SecurityPolicy,
+}
+
+impl Site {
+    fn new(spec: &SiteSpec) -> Site {
+        let prod_url: &str = spec.prod_url;
+        let canonical_host: String = match spec.redirect_mode {
+            RedirectMode::Www if !prod_url.starts_with("www.") => format!("www.{}", prod_url),
+            RedirectMode::Root => prod_url.strip_prefix("www.").unwrap_or(prod_url).to_string(),
+            _ => prod_url.to_string(),
+        };
+
+        Site {
+            redirect_mode: spec.redirect_mode,
+            routes: RouteTable::new(spec, &canonical_host),
+            redirects: spec.redirects.clone(),
+            normalize: spec.normalize.clone(),
+            security: spec.security.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SiteTable {
+    hosts: HashMap<String, Arc<Site>>,
+    default: Option<Arc<Site>>,
+}
+
+impl SiteTable {
+    pub fn new(config: &Config) -> SiteTable {
+        let mut hosts: HashMap<String, Arc<Site>> = HashMap::new();
+        for spec in config.tenants() {
+            let site: Arc<Site> = Arc::new(Site::new(&spec));
+            for host in config::site_hosts(spec.prod_url) {
+                hosts.insert(host, site.clone());
+            }
+        }
+
+        SiteTable {
+            hosts,
+            default: config.default_site().map(|spec: SiteSpec| Arc::new(Site::new(&spec))),
+        }
+    }
+
+    /// Finds the site for a `Host` value, ignoring case and any port.
+    pub fn resolve(&self, host: &str) -> Option<Arc<Site>> {
+        let (name, _) = forwarded::split_host_port(host);
+        self.hosts.get(&name).or(self.default.as_ref()).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> SiteTable {
+        let config: Config = toml::from_str(
+            r#"
+            [site]
+            staging_url = "https://main.webflow.io"
+            prod_url = "example.com"
+            base_url = "root"
+
+            [[sites]]
+            staging_url = "https://shop.webflow.io"
+            prod_url = "shop.example.com"
+            base_url = "www"
+            "#,
+        )
+        .unwrap();
+        SiteTable::new(&config)
+    }
+
+    fn is_shop(site: Option<Arc<Site>>) -> bool {
+        site.unwrap().redirect_mode == RedirectMode::Www
+    }
+
+    #[test]
+    fn hosts_match_without_case_or_port() {
+        let table: SiteTable = table();
+        assert!(is_shop(table.resolve("shop.example.com")));
+        assert!(is_shop(table.resolve("WWW.Shop.Example.com:8443")));
+        assert!(!is_shop(table.resolve("example.com")));
+    }
+
+    #[test]
+    fn ipv6_hosts_keep_their_brackets() {
+        let mut table: SiteTable = table();
+        let shop: Arc<Site> = table.hosts["shop.example.com"].clone();
+        table.hosts.insert("[::1]".to_string(), shop);
+        assert!(is_shop(table.resolve("[::1]:8080")));
+        assert!(is_shop(table.resolve("[::1]")));
+        assert!(!is_shop(table.resolve("[::2]:8080")));
+    }
+
+    #[test]
+    fn unknown_hosts_fall_through_to_the_default_site() {
+        let table: SiteTable = table();
+        assert!(!is_shop(table.resolve("other.example.org")));
+    }
+}
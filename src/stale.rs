@@ -0,0 +1,121 @@
+//! Last known good copies of pages, kept regardless of `Cache-Control` so something can
+//! still be served when Webflow errors, times out or returns a 5xx.
+
+use crate::cache::{self, Cache, CachedResponse, MemoryTier};
+use axum::body::Bytes;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use futures_util::Stream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+pub struct StaleStore {
+    memory: Mutex<MemoryTier>,
+    disk_dir: Option<PathBuf>,
+    max_object_bytes: usize,
+}
+
+impl StaleStore {
+    pub fn new(max_memory_bytes: usize, disk_dir: Option<PathBuf>, max_object_bytes: usize) -> StaleStore {
+        if let Some(dir) = &disk_dir {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                tracing::error!("Stale store: could not create {}: {}", dir.display(), e);
+            }
+        }
+
+        StaleStore {
+            memory: Mutex::new(MemoryTier::new(max_memory_bytes)),
+            disk_dir,
+            max_object_bytes,
+        }
+    }
+
+    /// Only successful HTML pages fetched with GET are worth keeping, and only when the cache
+    /// could share them too: no credentials or cookies on the request, nothing marked
+    /// `private` or `no-store` on the response.
+    pub fn should_keep(method: &Method, req_headers: &HeaderMap, status: StatusCode, headers: &HeaderMap) -> bool {
+        *method == Method::GET
+            && Cache::is_cacheable_request(method, req_headers)
+            && !req_headers.contains_key("cookie")
+            && status == StatusCode::OK
+            && !Cache::is_private_response(headers)
+            && headers
+                .get("content-type")
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .is_some_and(|v: &str| v.contains("text/html"))
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        if let Some(entry) = self.memory.lock().unwrap().get(key) {
+            return Some(entry);
+        }
+
+        let entry: Arc<CachedResponse> = Arc::new(cache::read_entry(self.disk_dir.as_ref()?, key).await?);
+        self.memory.lock().unwrap().insert(entry.clone());
+        Some(entry)
+    }
+
+    /// Streams a page to the client while recording it as the new last good copy.
+    /// `Set-Cookie` is dropped so one visitor's cookies are never replayed to another.
+    pub fn keep<S, E>(self: Arc<Self>, upstream: S, key: String, headers: &HeaderMap) -> impl Stream<Item = Result<Bytes, E>> + Send + Unpin + 'static
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + Unpin + 'static,
+        E: Send + 'static,
+    {
+        let mut headers: HeaderMap = headers.clone();
+        headers.remove("set-cookie");
+        let max: usize = self.max_object_bytes;
+
+        cache::tee(upstream, max, move |body: Bytes| {
+            let entry: Arc<CachedResponse> = Arc::new(CachedResponse::new(key, StatusCode::OK, &headers, body));
+            self.memory.lock().unwrap().insert(entry.clone());
+            if let Some(dir) = self.disk_dir.clone() {
+                tokio::spawn(async move {
+                    cache::write_entry(&dir, &entry).await;
+                });
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers: HeaderMap = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn html() -> HeaderMap {
+        headers(&[("content-type", "text/html; charset=utf-8")])
+    }
+
+    #[test]
+    fn keeps_public_html_pages() {
+        assert!(StaleStore::should_keep(&Method::GET, &HeaderMap::new(), StatusCode::OK, &html()));
+        assert!(!StaleStore::should_keep(&Method::GET, &HeaderMap::new(), StatusCode::OK, &headers(&[("content-type", "image/png")])));
+        assert!(!StaleStore::should_keep(&Method::GET, &HeaderMap::new(), StatusCode::NOT_FOUND, &html()));
+        assert!(!StaleStore::should_keep(&Method::HEAD, &HeaderMap::new(), StatusCode::OK, &html()));
+        assert!(!StaleStore::should_keep(&Method::POST, &HeaderMap::new(), StatusCode::OK, &html()));
+    }
+
+    #[test]
+    fn skips_requests_with_credentials() {
+        for (name, value) in [("cookie", "session=abc"), ("authorization", "Basic dXNlcjpwYXNz"), ("cache-control", "no-store")] {
+            assert!(!StaleStore::should_keep(&Method::GET, &headers(&[(name, value)]), StatusCode::OK, &html()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn skips_private_and_no_store_responses() {
+        for cc in ["private", "no-store", "max-age=60, private"] {
+            let resp: HeaderMap = headers(&[("content-type", "text/html"), ("cache-control", cc)]);
+            assert!(!StaleStore::should_keep(&Method::GET, &HeaderMap::new(), StatusCode::OK, &resp), "{}", cc);
+        }
+        let resp: HeaderMap = headers(&[("content-type", "text/html"), ("cache-control", "no-cache")]);
+        assert!(StaleStore::should_keep(&Method::GET, &HeaderMap::new(), StatusCode::OK, &resp));
+    }
+}
@@ -0,0 +1,183 @@
+//! OpenTelemetry tracing with W3C trace-context propagation.
+//!
+//! Spans are ordinary `tracing` spans with the `otel` target, which only the OpenTelemetry
+//! layer listens to, so they cost next to nothing when tracing is off and never show up in
+//! the logs. Incoming `traceparent`/`tracestate` make the proxy's spans children of the
+//! caller's trace, and the upstream request carries the proxy's own context onwards.
+
+use axum::extract::Request;
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, SdkTracer, SdkTracerProvider, SpanData, SpanExporter};
+use serde::Deserialize;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// The target every span meant for OpenTelemetry is created with.
+pub const SPAN_TARGET: &str = "otel";
+
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceExporter {
+    None,
+    /// OTLP over HTTP (protobuf) to `telemetry.endpoint`.
+    Otlp,
+    /// One JSON object per span, appended to `telemetry.file`. Meant for tests and debugging.
+    File,
+}
+
+pub struct Settings<'a> {
+    pub exporter: TraceExporter,
+    pub endpoint: Option<&'a str>,
+    pub file: Option<&'a Path>,
+    pub service_name: &'a str,
+    pub sample_ratio: f64,
+}
+
+/// Builds the tracer provider and installs the trace-context propagator, or returns `None`
+/// when tracing is off.
+pub fn init(settings: Settings) -> Result<Option<SdkTracerProvider>, String> {
+    let builder = SdkTracerProvider::builder()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(settings.sample_ratio))))
+        .with_resource(opentelemetry_sdk::Resource::builder().with_service_name(settings.service_name.to_string()).build());
+
+    let provider: SdkTracerProvider = match settings.exporter {
+        TraceExporter::None => return Ok(None),
+        TraceExporter::Otlp => {
+            let mut exporter = opentelemetry_otlp::SpanExporter::builder().with_http();
+            if let Some(endpoint) = settings.endpoint {
+                exporter = exporter.with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')));
+            }
+            let exporter: opentelemetry_otlp::SpanExporter =
+                exporter.build().map_err(|e: opentelemetry_otlp::ExporterBuildError| format!("telemetry.endpoint: {}", e))?;
+            builder.with_batch_exporter(exporter).build()
+        }
+        TraceExporter::File => {
+            let path: &Path = settings.file.ok_or("telemetry.file: is required when exporter is 'file'")?;
+            builder.with_batch_exporter(FileExporter::open(path)?).build()
+        }
+    };
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(Some(provider))
+}
+
+pub fn tracer(provider: &SdkTracerProvider) -> SdkTracer {
+    provider.tracer(env!("CARGO_PKG_NAME"))
+}
+
+/// Flushes spans still waiting in the batch. Blocks, so it runs off the async workers.
+pub async fn shutdown(provider: SdkTracerProvider) {
+    let result = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+    if let Ok(Err(e)) = result {
+        tracing::warn!("Could not flush traces: {}", e);
+    }
+}
+
+/// Middleware that opens the server span for a request, continuing the caller's trace when
+/// it sent a `traceparent`.
+pub async fn trace(request: Request, next: Next) -> Response {
+    let parent: opentelemetry::Context =
+        opentelemetry::global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(request.headers())));
+
+    let span: tracing::Span = tracing::info_span!(
+        target: SPAN_TARGET,
+        "request",
+        otel.name = %request.method(),
+        otel.kind = "server",
+        otel.status_code = tracing::field::Empty,
+        http.request.method = %request.method(),
+        url.path = request.uri().path(),
+        http.response.status_code = tracing::field::Empty,
+    );
+    // Fails only when tracing is off, in which case there is nothing to attach to.
+    let _ = span.set_parent(parent);
+
+    let response: Response = next.run(request).instrument(span.clone()).await;
+    span.record("http.response.status_code", response.status().as_u16());
+    if response.status().is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+    response
+}
+
+/// The `traceparent` (and `tracestate`) for a request made inside the current span. Empty
+/// when tracing is off, so the client's own headers are passed through instead.
+pub fn upstream_headers() -> HeaderMap {
+    let mut headers: HeaderMap = HeaderMap::new();
+    let context: opentelemetry::Context = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut HeaderInjector(&mut headers)));
+    headers
+}
+
+/// Appends finished spans to a file as JSON lines.
+#[derive(Debug)]
+struct FileExporter {
+    file: Mutex<std::fs::File>,
+}
+
+impl FileExporter {
+    fn open(path: &Path) -> Result<FileExporter, String> {
+        let file: std::fs::File = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e: std::io::Error| format!("telemetry.file: could not open '{}': {}", path.display(), e))?;
+        Ok(FileExporter { file: Mutex::new(file) })
+    }
+
+    fn write(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let mut out: Vec<u8> = Vec::new();
+        for span in batch {
+            let attributes: serde_json::Map<String, serde_json::Value> = span
+                .attributes
+                .iter()
+                .map(|kv: &opentelemetry::KeyValue| (kv.key.to_string(), serde_json::Value::String(kv.value.to_string())))
+                .collect();
+            let line: serde_json::Value = serde_json::json!({
+                "trace_id": span.span_context.trace_id().to_string(),
+                "span_id": span.span_context.span_id().to_string(),
+                "parent_span_id": span.parent_span_id.to_string(),
+                "name": span.name,
+                "kind": format!("{:?}", span.span_kind).to_ascii_lowercase(),
+                "start_unix_nano": unix_nanos(span.start_time),
+                "end_unix_nano": unix_nanos(span.end_time),
+                "status": match &span.status {
+                    opentelemetry::trace::Status::Unset => "unset",
+                    opentelemetry::trace::Status::Ok => "ok",
+                    opentelemetry::trace::Status::Error { .. } => "error",
+                },
+                "attributes": attributes,
+            });
+            out.extend_from_slice(line.to_string().as_bytes());
+            out.push(b'\n');
+        }
+
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(&out)
+            .map_err(|e: std::io::Error| OTelSdkError::InternalFailure(e.to_string()))
+    }
+}
+
+impl SpanExporter for FileExporter {
+    fn export(&self, batch: Vec<SpanData>) -> impl std::future::Future<Output = OTelSdkResult> + Send {
+        std::future::ready(self.write(batch))
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).map(|d: std::time::Duration| d.as_nanos()).unwrap_or(0)
+}
+
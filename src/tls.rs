@@ -0,0 +1,165 @@
+//! TLS termination with rustls: certificates chosen by SNI and reloaded from disk.
+//!
+//! A connection gets the certificate for its exact server name, then a `*.` wildcard one
+//! level up, then the default certificate. File certificates are checked for changes every
+//! `tls.reload_secs`; a renewed pair is swapped in for new connections, and one that fails
+//! to load leaves the previous certificate serving. ACME certificates go into the same store.
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// The ALPN protocol of TLS-ALPN-01 validation connections (RFC 8737).
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Every certificate the TLS listeners can present.
+#[derive(Default)]
+pub struct CertStore {
+    hosts: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    default: RwLock<Option<Arc<CertifiedKey>>>,
+    /// TLS-ALPN-01 challenge certificates, only ever presented to `acme-tls/1` connections.
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertStore {
+    pub fn set(&self, host: &str, key: Arc<CertifiedKey>) {
+        self.hosts.write().unwrap().insert(host.to_ascii_lowercase(), key);
+    }
+
+    pub fn set_default(&self, key: Arc<CertifiedKey>) {
+        *self.default.write().unwrap() = Some(key);
+    }
+
+    pub fn set_challenge(&self, host: &str, key: Arc<CertifiedKey>) {
+        self.challenges.write().unwrap().insert(host.to_ascii_lowercase(), key);
+    }
+
+    pub fn remove_challenge(&self, host: &str) {
+        self.challenges.write().unwrap().remove(&host.to_ascii_lowercase());
+    }
+}
+
+impl fmt::Debug for CertStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertStore")
+            .field("hosts", &self.hosts.read().unwrap().keys().collect::<Vec<&String>>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name: Option<String> = hello.server_name().map(|name: &str| name.to_ascii_lowercase());
+
+        if hello.alpn().is_some_and(|mut protocols| protocols.any(|protocol: &[u8]| protocol == ACME_TLS_ALPN)) {
+            return self.challenges.read().unwrap().get(name.as_deref()?).cloned();
+        }
+
+        if let Some(name) = &name {
+            let hosts = self.hosts.read().unwrap();
+            let wildcard: Option<String> = name.split_once('.').map(|(_, parent)| format!("*.{}", parent));
+            if let Some(key) = hosts.get(name).or_else(|| wildcard.and_then(|wildcard: String| hosts.get(&wildcard))) {
+                return Some(key.clone());
+            }
+        }
+        self.default.read().unwrap().clone()
+    }
+}
+
+/// A certificate chain and key on disk, for the given hosts or, with none, the default.
+#[derive(Clone)]
+pub struct FileCert {
+    pub hosts: Vec<String>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Parses a PEM certificate chain and private key.
+pub fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<CertifiedKey, String> {
+    let chain: Vec<CertificateDer<'static>> = CertificateDer::pem_slice_iter(cert_pem)
+        .collect::<Result<Vec<CertificateDer<'static>>, _>>()
+        .map_err(|e: rustls::pki_types::pem::Error| format!("bad certificate: {}", e))?;
+    if chain.is_empty() {
+        return Err("no certificate found".to_string());
+    }
+    let key: PrivateKeyDer<'static> = PrivateKeyDer::from_pem_slice(key_pem).map_err(|e: rustls::pki_types::pem::Error| format!("bad private key: {}", e))?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key).map_err(|e: rustls::Error| format!("unsupported private key: {}", e))?;
+    let certified: CertifiedKey = CertifiedKey::new(chain, signing_key);
+    certified.keys_match().map_err(|e: rustls::Error| format!("private key does not match the certificate: {}", e))?;
+    Ok(certified)
+}
+
+fn load_file_cert(file: &FileCert) -> Result<CertifiedKey, String> {
+    let read = |path: &Path| -> Result<Vec<u8>, String> { std::fs::read(path).map_err(|e: std::io::Error| format!("could not read '{}': {}", path.display(), e)) };
+    certified_key(&read(&file.cert)?, &read(&file.key)?).map_err(|e: String| format!("{}: {}", file.cert.display(), e))
+}
+
+fn install(store: &CertStore, file: &FileCert, key: CertifiedKey) {
+    let key: Arc<CertifiedKey> = Arc::new(key);
+    if file.hosts.is_empty() {
+        store.set_default(key.clone());
+    }
+    for host in &file.hosts {
+        store.set(host, key.clone());
+    }
+}
+
+/// Loads every file certificate, so a broken one fails startup.
+pub fn load_files(store: &CertStore, files: &[FileCert]) -> Result<(), String> {
+    for file in files {
+        install(store, file, load_file_cert(file)?);
+    }
+    Ok(())
+}
+
+/// Re-reads a certificate whenever its chain or key file changes.
+pub fn spawn_reload(store: Arc<CertStore>, files: Vec<FileCert>, interval: Duration) {
+    let modified = |file: &FileCert| -> Option<(SystemTime, SystemTime)> {
+        let mtime = |path: &Path| std::fs::metadata(path).and_then(|meta: std::fs::Metadata| meta.modified()).ok();
+        Some((mtime(&file.cert)?, mtime(&file.key)?))
+    };
+
+    tokio::spawn(async move {
+        let mut seen: Vec<Option<(SystemTime, SystemTime)>> = files.iter().map(modified).collect();
+        loop {
+            tokio::time::sleep(interval).await;
+            for (file, seen) in files.iter().zip(seen.iter_mut()) {
+                let current: Option<(SystemTime, SystemTime)> = modified(file);
+                if current.is_none() || current == *seen {
+                    continue;
+                }
+                // Caught between writing the chain and the key, this fails until the second
+                // write changes the times again.
+                *seen = current;
+                match load_file_cert(file) {
+                    Ok(key) => {
+                        install(&store, file, key);
+                        tracing::info!("Reloaded certificate {}", file.cert.display());
+                    }
+                    Err(e) => tracing::warn!("Could not reload certificate, keeping the previous one: {}", e),
+                }
+            }
+        }
+    });
+}
+
+/// The TLS listeners' config. Offers HTTP/2 ahead of HTTP/1.1 when `http2` is set.
+pub fn server_config(store: Arc<CertStore>, http2: bool) -> Arc<ServerConfig> {
+    let mut config: ServerConfig = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("the ring provider supports the default protocol versions")
+        .with_no_client_auth()
+        .with_cert_resolver(store);
+    config.alpn_protocols = vec![b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+    if http2 {
+        config.alpn_protocols.insert(0, b"h2".to_vec());
+    }
+    Arc::new(config)
+}
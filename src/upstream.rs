@@ -0,0 +1,201 @@
+//! Resilience for upstream requests: the first-byte timeout, retries and circuit breaking.
+//!
+//! Only idempotent requests without a body are retried, since a streamed body cannot be sent
+//! twice, and only after failures that say nothing reached the application: connect errors,
+//! timeouts and 502/503/504. Each upstream origin has one breaker shared by every route and
+//! site using it. After enough consecutive failures it opens and requests fail at once, going
+//! straight to the stale copy or fallback page, until a single trial request gets through.
+
+use axum::http::{Method, StatusCode};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub enum UpstreamError {
+    Request(reqwest::Error),
+    FirstByteTimeout(Duration),
+    CircuitOpen,
+}
+
+impl UpstreamError {
+    /// The `kind` label of `proxy_upstream_errors_total`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            UpstreamError::Request(e) => error_kind(e),
+            UpstreamError::FirstByteTimeout(_) => "timeout",
+            UpstreamError::CircuitOpen => "circuit_open",
+        }
+    }
+
+    fn is_retryable(&self) -> bool {
+        match self {
+            UpstreamError::Request(e) => e.is_connect() || e.is_timeout(),
+            UpstreamError::FirstByteTimeout(_) => true,
+            UpstreamError::CircuitOpen => false,
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Request(e) => write!(f, "{}", e),
+            UpstreamError::FirstByteTimeout(timeout) => write!(f, "no response within {}s", timeout.as_secs()),
+            UpstreamError::CircuitOpen => write!(f, "circuit open, upstream not contacted"),
+        }
+    }
+}
+
+pub fn error_kind(error: &reqwest::Error) -> &'static str {
+    if error.is_timeout() {
+        "timeout"
+    } else if error.is_connect() {
+        "connect"
+    } else if error.is_redirect() {
+        "redirect"
+    } else if error.is_body() {
+        "body"
+    } else if error.is_decode() {
+        "decode"
+    } else if error.is_request() {
+        "request"
+    } else if error.is_builder() {
+        "builder"
+    } else {
+        "other"
+    }
+}
+
+#[derive(Clone)]
+pub struct Policy {
+    /// How long to wait for the response headers, on every attempt.
+    pub first_byte_timeout: Duration,
+    pub retries: u32,
+    pub backoff: Duration,
+    pub backoff_max: Duration,
+}
+
+impl Policy {
+    pub fn may_retry(method: &Method, has_body: bool) -> bool {
+        !has_body && matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE | Method::TRACE)
+    }
+
+    pub fn should_retry(&self, attempt: u32, result: &Result<reqwest::Response, UpstreamError>) -> bool {
+        attempt < self.retries
+            && match result {
+                Ok(response) => matches!(
+                    response.status(),
+                    StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+                ),
+                Err(e) => e.is_retryable(),
+            }
+    }
+
+    /// Exponential backoff with full jitter: anywhere between zero and `backoff * 2^(attempt - 1)`,
+    /// capped at `backoff_max`, so retries from many requests don't arrive together.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling: Duration = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.backoff_max);
+        Duration::from_millis(fastrand::u64(0..=ceiling.as_millis() as u64))
+    }
+}
+
+/// The breakers for every upstream, created on first use.
+pub struct Breakers {
+    threshold: u64,
+    open_for: Duration,
+    breakers: Mutex<HashMap<String, Arc<CircuitBreaker>>>,
+}
+
+impl Breakers {
+    /// `threshold` consecutive failures open a breaker for `open_for`; 0 turns breaking off.
+    pub fn new(threshold: u64, open_for: Duration) -> Breakers {
+        Breakers {
+            threshold,
+            open_for,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, upstream: &str) -> Arc<CircuitBreaker> {
+        self.breakers
+            .lock()
+            .unwrap()
+            .entry(upstream.to_string())
+            .or_insert_with(|| {
+                Arc::new(CircuitBreaker {
+                    upstream: upstream.to_string(),
+                    threshold: self.threshold,
+                    open_for: self.open_for,
+                    state: Mutex::new(BreakerState::Closed { failures: 0 }),
+                })
+            })
+            .clone()
+    }
+}
+
+enum BreakerState {
+    Closed { failures: u64 },
+    Open { until: Instant },
+    /// One trial request is out. Another is let through at `retry_at` if it never reports
+    /// back, such as when the client went away mid-request.
+    HalfOpen { retry_at: Instant },
+}
+
+pub struct CircuitBreaker {
+    upstream: String,
+    threshold: u64,
+    open_for: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    /// Whether a request may go out now.
+    pub fn allow(&self) -> bool {
+        if self.threshold == 0 {
+            return true;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let now: Instant = Instant::now();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } | BreakerState::HalfOpen { retry_at: until } if now < until => false,
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => {
+                *state = BreakerState::HalfOpen {
+                    retry_at: now + self.open_for,
+                };
+                true
+            }
+        }
+    }
+
+    /// Records the outcome of a request that `allow` let through.
+    pub fn record(&self, success: bool) {
+        if self.threshold == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        match (&*state, success) {
+            (BreakerState::Closed { .. }, true) => *state = BreakerState::Closed { failures: 0 },
+            (_, true) => {
+                tracing::info!("Circuit for {} closed, upstream recovered", self.upstream);
+                *state = BreakerState::Closed { failures: 0 };
+            }
+            (BreakerState::Closed { failures }, false) if failures + 1 < self.threshold => {
+                *state = BreakerState::Closed { failures: failures + 1 };
+            }
+            (BreakerState::Open { .. }, false) => {}
+            (_, false) => {
+                tracing::warn!("Circuit for {} open for {}s after repeated failures", self.upstream, self.open_for.as_secs());
+                *state = BreakerState::Open {
+                    until: Instant::now() + self.open_for,
+                };
+            }
+        }
+    }
+}